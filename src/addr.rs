@@ -12,23 +12,48 @@ pub struct Addr {
     pub port: Option<u16>,
     pub root: String,
     pub digest: String,
+    /// The `key=value` options written after the address in the list.
+    pub options: Vec<(String, String)>,
 }
 
+/// The options accepted after an address in the server list.
+const OPTIONS: &[&str] = &["exclude", "include", "bwlimit", "pre", "post"];
+
 impl Addr {
     pub fn connect(&self) -> Result<TcpStream, String> {
         let mut a = self.host.to_string();
-        a.push_str(":");
+        a.push(':');
         match self.port {
             Some(p) => a.push_str(&p.to_string()),
             None => a.push_str("22"),
         };
         TcpStream::connect(&a).map_err(|err| format!("Fail to connect to {:?}: {}", a, err))
     }
+    /// Check if `name` designates this address: the host, `user@host[:port]`,
+    /// the full address or the digest.
+    pub fn is(&self, name: &str) -> bool {
+        name == self.host
+            || name == self.digest
+            || name == format!("{:x}", self)
+            || name == self.to_string()
+    }
+    /// All the values of the option `key`, in the list order.
+    pub fn option<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.options
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
 }
 
 impl std::convert::TryFrom<&str> for Addr {
     type Error = String;
-    fn try_from(s: &str) -> Result<Self, Self::Error> {
+    fn try_from(line: &str) -> Result<Self, Self::Error> {
+        let (s, options) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], parse_options(&line[i..])?),
+            None => (line, Vec::new()),
+        };
+
         lazy_static! {
             static ref RE: Regex =
                 Regex::new(r"(?P<u>\w+)@(?P<h>\[[0-9a-f:]+\]|[^:/]+)(:(?P<p>\d{1,4}))?(?P<r>/.+)")
@@ -47,16 +72,74 @@ impl std::convert::TryFrom<&str> for Addr {
         Ok(Addr {
             user: RE.replace(s, "$u").to_string(),
             host: RE.replace(s, "$h").to_string(),
-            port: if port.len() == 0 {
+            port: if port.is_empty() {
                 None
             } else {
                 Some(port.parse().unwrap())
             },
             root: RE.replace(s, "$r").to_string(),
             digest: d,
+            options,
         })
     }
 }
+
+/// Parse the options `key=value` or `key="value with space"`.
+fn parse_options(s: &str) -> Result<Vec<(String, String)>, String> {
+    let mut options = Vec::new();
+    let mut chars = s.trim().chars().peekable();
+    while chars.peek().is_some() {
+        let key: String = chars.by_ref().take_while(|&c| c != '=').collect();
+        if !OPTIONS.contains(&key.as_str()) {
+            return Err(format!("Unknown option {:?}", key));
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => value.extend(chars.next()),
+                    Some(c) => value.push(c),
+                    None => return Err(format!("Unclosed quote in option {:?}", key)),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek().filter(|c| !c.is_whitespace()) {
+                value.push(c);
+                chars.next();
+            }
+        }
+
+        if value.is_empty() {
+            return Err(format!("Empty value for option {:?}", key));
+        }
+        options.push((key, value));
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+    }
+    Ok(options)
+}
+#[test]
+fn addr_options() {
+    let a = Addr::try_from(r#"u@h/r exclude=*.tmp include="a b/\"c\"""#).unwrap();
+    assert_eq!(a.root, "/r");
+    assert_eq!(a.digest, Addr::try_from("u@h/r").unwrap().digest);
+    assert_eq!(a.option("exclude").collect::<Vec<_>>(), vec!["*.tmp"]);
+    assert_eq!(a.option("include").collect::<Vec<_>>(), vec![r#"a b/"c""#]);
+
+    let a = Addr::try_from(r#"u@h/r pre="pg_dump -f db.sql" pre=sync post=true"#).unwrap();
+    assert_eq!(
+        a.option("pre").collect::<Vec<_>>(),
+        vec!["pg_dump -f db.sql", "sync"]
+    );
+
+    assert!(Addr::try_from("u@h/r unknown=1").is_err());
+    assert!(Addr::try_from("u@h/r exclude=").is_err());
+    assert!(Addr::try_from(r#"u@h/r exclude="a"#).is_err());
+}
 #[test]
 fn addr_try_from() {
     let mut a = Addr {
@@ -65,6 +148,7 @@ fn addr_try_from() {
         port: Some(22),
         root: "/home/u/dir/".to_string(),
         digest: "1210b4c0432588ea4c9beefbb7b2278e".to_string(),
+        options: Vec::new(),
     };
     assert_eq!(Addr::try_from(a.to_string().as_str()).unwrap(), a);
 
@@ -92,7 +176,7 @@ pub fn addr_from_reader<R: std::io::Read>(r: R) -> impl std::iter::Iterator<Item
                 .to_string()
         })
         .enumerate()
-        .filter(|(_, l)| l.len() > 0)
+        .filter(|(_, l)| !l.is_empty())
         .filter_map(|(i, l)| match Addr::try_from(l.as_str()) {
             Err(err) => {
                 eprintln!("line {}: {} ", i + 1, err);
@@ -118,6 +202,7 @@ fn addr_display() {
         port: Some(22),
         root: "/home/u/dir/".to_string(),
         digest: "".to_string(),
+        options: Vec::new(),
     };
     assert_eq!(&format!("addr: {}", &a), "addr: u@h:22/home/u/dir/");
     a.port = None;
@@ -140,8 +225,11 @@ fn addr_display_lower_hex() {
         port: Some(22),
         root: "/home/u/dir/".to_string(),
         digest: "".to_string(),
+        options: Vec::new(),
     };
     assert_eq!(&format!("addr: {:x}", &a), "addr: u@h:22");
     a.port = None;
     assert_eq!(&format!("addr: {:x}", &a), "addr: u@h");
+    assert!(a.is("h") && a.is("u@h") && a.is("u@h/home/u/dir/"));
+    assert!(!a.is("u@h:22") && !a.is("x"));
 }
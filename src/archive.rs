@@ -0,0 +1,322 @@
+use super::{exclude, list_remote, snapshot, state, Addr, Assets, Config, Entry, Filter, Index, R};
+use separator::Separatable;
+use std::fs::{create_dir_all, rename, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// The compression of the archives, by an external program.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!(
+                "Unknown compression {:?}, expected none, gzip or zstd",
+                s
+            )),
+        }
+    }
+}
+
+/// The options of the `archive` command.
+#[derive(Debug, Default, Clone)]
+pub struct Archive {
+    pub compression: Compression,
+    /// Only the files changed since the last archive.
+    pub incremental: bool,
+    /// The directory of the archives.
+    pub output: PathBuf,
+}
+
+/* TAR */
+
+const BLOCK: usize = 512;
+
+/// Write a number in octal, or in base-256 if it's too big for the field.
+fn octal(field: &mut [u8], v: u64) {
+    let digits = field.len() - 1;
+    if digits < 22 && v >= 1 << (3 * digits) {
+        for (i, b) in field.iter_mut().rev().enumerate() {
+            *b = (v.checked_shr(8 * i as u32).unwrap_or(0) & 0xff) as u8;
+        }
+        field[0] = 0x80;
+        return;
+    }
+    let s = format!("{:0width$o}\0", v, width = digits);
+    field.copy_from_slice(s.as_bytes());
+}
+
+/// One ustar header block.
+fn block(name: &[u8], kind: u8, mode: u32, size: u64, mtime: u64, link: &[u8]) -> [u8; BLOCK] {
+    let mut h = [0u8; BLOCK];
+    h[..name.len().min(100)].copy_from_slice(&name[..name.len().min(100)]);
+    octal(&mut h[100..108], (mode & 0o7777) as u64);
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    octal(&mut h[124..136], size);
+    octal(&mut h[136..148], mtime);
+    h[156] = kind;
+    h[157..157 + link.len().min(100)].copy_from_slice(&link[..link.len().min(100)]);
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+
+    h[148..156].copy_from_slice(b"        ");
+    let sum: u64 = h.iter().map(|&b| b as u64).sum();
+    let s = format!("{:06o}\0 ", sum);
+    h[148..156].copy_from_slice(s.as_bytes());
+    h
+}
+
+/// The header of an entry, with the GNU long name and long link entries
+/// before it if they don't fit.
+fn header(name: &str, kind: u8, mode: u32, size: u64, mtime: u64, link: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(BLOCK);
+    for (long, value) in [(b'L', name), (b'K', link)].iter() {
+        if value.len() > 100 {
+            let data = value.len() as u64 + 1;
+            out.extend_from_slice(&block(b"././@LongLink", *long, 0, data, 0, b""));
+            out.extend_from_slice(value.as_bytes());
+            out.resize(out.len() + padding(data) as usize + 1, 0);
+        }
+    }
+    out.extend_from_slice(&block(
+        name.as_bytes(),
+        kind,
+        mode,
+        size,
+        mtime,
+        link.as_bytes(),
+    ));
+    out
+}
+
+/// The zeros after `size` bytes of data to fill the last block.
+fn padding(size: u64) -> u64 {
+    (BLOCK as u64 - size % BLOCK as u64) % BLOCK as u64
+}
+#[test]
+fn test_tar_header() {
+    let h = header("dir/a.txt", b'0', 0o100644, 42, 1600000000, "");
+    assert_eq!(h.len(), BLOCK);
+    assert_eq!(&h[..9], b"dir/a.txt");
+    assert_eq!(&h[100..108], b"0000644\0");
+    assert_eq!(&h[124..136], b"00000000052\0");
+    assert_eq!(&h[136..148], b"13727410000\0");
+    assert_eq!(&h[257..263], b"ustar\0");
+    let sum: u64 = h[..148]
+        .iter()
+        .chain(b"        ".iter())
+        .chain(h[156..].iter())
+        .map(|&b| b as u64)
+        .sum();
+    assert_eq!(&h[148..156], format!("{:06o}\0 ", sum).as_bytes());
+
+    let long = "d/".repeat(60);
+    let h = header(&long, b'2', 0o777, 0, 0, "target");
+    assert_eq!(h.len(), 3 * BLOCK);
+    assert_eq!(h[156], b'L');
+    assert_eq!(&h[BLOCK..BLOCK + 120], long.as_bytes());
+    assert_eq!(h[2 * BLOCK + 156], b'2');
+    assert_eq!(&h[2 * BLOCK + 157..2 * BLOCK + 163], b"target");
+
+    let mut f = [0u8; 12];
+    octal(&mut f, 1 << 40);
+    assert_eq!(f[0], 0x80);
+    assert_eq!(&f[6..], &[1, 0, 0, 0, 0, 0]);
+    assert_eq!(padding(0), 0);
+    assert_eq!(padding(1), 511);
+    assert_eq!(padding(512), 0);
+}
+
+/* ARCHIVE */
+
+/// The output of the archive, through the compression program if any.
+struct Output {
+    w: BufWriter<Box<dyn Write + Send>>,
+    child: Option<Child>,
+}
+
+impl Output {
+    fn create(path: &PathBuf, c: Compression) -> Result<Output, String> {
+        let file = File::create(path).map_err(|err| format!("Create {:?} fail: {}", path, err))?;
+        let program = match c {
+            Compression::None => {
+                return Ok(Output {
+                    w: BufWriter::new(Box::new(file)),
+                    child: None,
+                })
+            }
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        };
+        let mut child = Command::new(program)
+            .arg("-c")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::from(file))
+            .spawn()
+            .map_err(|err| format!("Run {:?} fail: {}", program, err))?;
+        Ok(Output {
+            w: BufWriter::new(Box::new(child.stdin.take().unwrap())),
+            child: Some(child),
+        })
+    }
+    fn finish(self) -> R {
+        let Output { w, child } = self;
+        w.into_inner()
+            .map_err(|err| format!("Write the archive fail: {}", err.error()))?;
+        match child.map(|mut c| c.wait()) {
+            Some(Ok(s)) if !s.success() => Err(format!("The compression fail: {}", s)),
+            Some(Err(err)) => Err(format!("The compression fail: {}", err)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Write the remote tree of the host in a tar file, in the output directory.
+pub fn archive(a: Addr, c: Arc<Config>, o: &Archive) -> R {
+    let assets = Assets::new(a, c)?;
+    create_dir_all(&o.output).map_err(|err| format!("Create {:?} fail: {}", o.output, err))?;
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let path = o.output.join(format!(
+        "{}-{}{}.tar{}",
+        assets.a.digest,
+        snapshot::name(now),
+        if o.incremental { "-incremental" } else { "" },
+        match o.compression {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    ));
+    let partial = PathBuf::from(format!("{}.partial", path.to_string_lossy()));
+
+    let index_path = state(&assets.a, "archive");
+    let old = match o.incremental {
+        true => Index::load(&index_path)?,
+        false => Index::default(),
+    };
+    let mut new = Index::default();
+
+    let mut out = Output::create(&partial, o.compression)?;
+    let (count, bytes) = write_tree(&assets, &old, &mut new, &mut out.w)
+        .map_err(|err| format!("Write {:?} fail: {}", partial, err))?;
+    out.w
+        .write_all(&[0u8; 2 * BLOCK])
+        .map_err(|err| format!("Write {:?} fail: {}", partial, err))?;
+    out.finish()?;
+    // An archive with missing entries stays partial.
+    assets
+        .result()
+        .map_err(|err| format!("{}, {:?} is kept partial", err, partial))?;
+
+    rename(&partial, &path).map_err(|err| format!("Rename {:?} fail: {}", partial, err))?;
+    assets.ms(
+        "archive",
+        &format!(
+            "{:?}: {} files, {} o",
+            path,
+            count.separated_string(),
+            bytes.separated_string()
+        ),
+    );
+    new.save(&index_path)
+}
+
+/// Write the entries of the remote tree, return the number of files and
+/// their size.
+fn write_tree<W: Write>(
+    a: &Assets,
+    old: &Index,
+    new: &mut Index,
+    w: &mut W,
+) -> io::Result<(u64, u64)> {
+    let (mut count, mut bytes) = (0, 0);
+    let mut queue = vec![(PathBuf::from(&a.a.root), Filter::new(&a.c, &a.a))];
+    while let Some((dir, filter)) = queue.pop() {
+        a.log("index", &dir, None);
+        let mut list = match list_remote(a, &dir) {
+            Ok(list) => exclude(list, &filter),
+            Err(err) => {
+                a.err(err);
+                continue;
+            }
+        };
+        list.sort_by(|x, y| x.name.cmp(&y.name));
+
+        for f in list.into_iter() {
+            let p = dir.join(&f.name);
+            let name = filter.path(&f.name);
+            let e = Entry {
+                dir: f.dir,
+                remote: (f.size, f.mtime),
+                ..Entry::default()
+            };
+            // An entry is in the new index once it's written, the others
+            // are archived again next time.
+            let changed = old.get(&name).is_none_or(|o| o.remote_changed(&f));
+
+            if f.dir {
+                w.write_all(&header(&format!("{}/", name), b'5', f.mode, 0, f.mtime, ""))?;
+                new.insert(name, e);
+                queue.push((p, filter.enter(&f.name)));
+                continue;
+            }
+            if !changed {
+                new.insert(name, e);
+                continue;
+            }
+            if f.symlink() {
+                match a.sftp.readlink(&p) {
+                    Ok(target) => {
+                        a.log("link", &p, None);
+                        let target = target.to_string_lossy();
+                        w.write_all(&header(&name, b'2', f.mode, 0, f.mtime, &target))?;
+                        new.insert(name, e);
+                    }
+                    Err(err) => a.err(format!("Read the link {:?} fail {}", p, err)),
+                }
+                continue;
+            }
+
+            let mut file = match a.sftp.open(&p) {
+                Ok(file) => file,
+                Err(err) => {
+                    a.err(format!("Open remote file {:?} fail {}", p, err));
+                    continue;
+                }
+            };
+            a.log("archive", &p, Some(f.size));
+            w.write_all(&header(&name, b'0', f.mode, f.size, f.mtime, ""))?;
+            let copied = io::copy(&mut (&mut file).take(f.size), w)?;
+            if copied < f.size {
+                a.err(format!(
+                    "{:?} shrank during the archive, filled with zeros",
+                    p
+                ));
+                io::copy(&mut io::repeat(0).take(f.size - copied), w)?;
+            } else {
+                new.insert(name, e);
+            }
+            w.write_all(&vec![0u8; padding(f.size) as usize])?;
+            count += 1;
+            bytes += f.size;
+        }
+    }
+    Ok((count, bytes))
+}
@@ -1,25 +1,69 @@
-use super::{Addr, PathBuf, Session, Sftp};
+use super::{state, Addr, Config, Index, Owners, Session, Sftp, Tracker, Transfers, R};
 use separator::Separatable;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Instant;
 
 pub type Key = Option<String>;
 
 pub struct Assets {
     pub a: Addr,
-    pub sftp: Sftp,
-    pub ansi: bool,
+    /// The SSH session of the main channel, to run remote commands.
+    pub session: Session,
+    /// The main channel, for the listings and the small operations.
+    pub sftp: Arc<Sftp>,
+    /// The workers that copy the files on several channels.
+    pub transfers: Transfers,
+    pub c: Arc<Config>,
     pub before: Instant,
+    /// The index of the last run and the one of this run.
+    pub index: Tracker,
+    /// The number of operations that modified a side.
+    pub actions: AtomicU64,
+    /// The number of errors printed, with the ones of the transfers.
+    pub errors: Arc<AtomicU64>,
+    /// The owners of the files, with `--owner`.
+    pub owners: Option<Arc<Owners>>,
+    /// Don't print each operation, only the errors and the results.
+    pub quiet: bool,
 }
 impl Assets {
-    pub fn new(a: Addr, ansi: bool, key: Key) -> Result<Assets, String> {
-        Ok(Assets {
-            before: Instant::now(),
-            ansi: ansi,
-            sftp: Assets::connect(&a, key)?,
-            a: a,
-        })
+    pub fn new(a: Addr, c: Arc<Config>) -> Result<Assets, String> {
+        let before = Instant::now();
+        let session = Assets::session(&a, &c.key)?;
+        let sftp = Arc::new(
+            session
+                .sftp()
+                .map_err(|err| format!("Open SFTP fail for {}: {}", a, err))?,
+        );
+        let errors = Arc::new(AtomicU64::new(0));
+        let mut assets = Assets {
+            before,
+            session,
+            transfers: Transfers::new(&a, &c, sftp.clone(), errors.clone())?,
+            sftp,
+            index: Tracker::new(Index::load(&state(&a, "index"))?),
+            actions: AtomicU64::new(0),
+            errors,
+            owners: None,
+            quiet: false,
+            c,
+            a,
+        };
+        if let Some(owner) = assets.c.owner {
+            assets.owners = Some(Arc::new(Owners::new(&assets, owner)?));
+        }
+        Ok(assets)
     }
-    pub fn connect(a: &Addr, key: Key) -> Result<Sftp, String> {
+    pub fn connect(a: &Addr, key: &Key) -> Result<Sftp, String> {
+        Assets::session(a, key)?
+            .sftp()
+            .map_err(|err| format!("Open SFTP fail for {}: {}", a, err))
+    }
+    /// Open an authenticated SSH session.
+    pub fn session(a: &Addr, key: &Key) -> Result<Session, String> {
         let mut s = Session::new()
             .map_err(|err| format!("The creation of a new SSH session fail: {}", err))?;
         s.set_compress(true);
@@ -29,18 +73,39 @@ impl Assets {
 
         match key {
             Some(k) => s
-                .userauth_pubkey_memory(&a.user, None, &k, None)
+                .userauth_pubkey_memory(&a.user, None, k, None)
                 .map_err(|err| format!("Authentification with key fail: {}", err))?,
             None => s
                 .userauth_agent(&a.user)
                 .map_err(|err| format!("Authentification fail for {}: {}", a, err))?,
         };
+        Ok(s)
+    }
+    /// Run a command on the remote host, return its standard output. A non
+    /// zero exit status is an error with the standard error.
+    pub fn exec(&self, cmd: &str) -> Result<String, String> {
+        let mut ch = self
+            .session
+            .channel_session()
+            .map_err(|err| format!("Open channel fail for {}: {}", self.a, err))?;
+        ch.exec(cmd)
+            .map_err(|err| format!("Run {:?} fail: {}", cmd, err))?;
 
-        s.sftp()
-            .map_err(|err| format!("Open SFTP fail for {}: {}", a, err))
+        let mut out = String::new();
+        let mut err = String::new();
+        ch.read_to_string(&mut out)
+            .and_then(|_| ch.stderr().read_to_string(&mut err))
+            .map_err(|err| format!("Read the output of {:?} fail: {}", cmd, err))?;
+        ch.wait_close()
+            .map_err(|err| format!("Close the channel of {:?} fail: {}", cmd, err))?;
+        match ch.exit_status() {
+            Ok(0) => Ok(out),
+            Ok(code) => Err(format!("{:?} exit with {}: {}", cmd, code, err.trim_end())),
+            Err(err) => Err(format!("Get the exit status of {:?} fail: {}", cmd, err)),
+        }
     }
     pub fn ms(&self, op: &str, ms: &str) {
-        if self.ansi {
+        if self.c.ansi {
             println!(
                 "\x1b[1m{:>12} \x1b[1;34m{:x}\x1b[36m{}\x1b[0m {}",
                 op, self.a, self.a.root, ms
@@ -49,15 +114,23 @@ impl Assets {
             println!("{:>12}: <{:x}> {} {}", op, self.a, self.a.root, ms)
         }
     }
-    pub fn log(&self, op: &str, path: &PathBuf, size: Option<u64>) {
+    /// Log an operation that modifies a side, and count it.
+    pub fn act(&self, op: &str, path: &Path, size: Option<u64>) {
+        self.actions.fetch_add(1, Ordering::SeqCst);
+        self.log(op, path, size);
+    }
+    pub fn log(&self, op: &str, path: &Path, size: Option<u64>) {
+        if self.quiet {
+            return;
+        }
         let p = path.to_str().unwrap_or("");
 
         let s = match size {
             Some(size) => format!(" ({} o)", size.separated_string()),
-            None => format!(""),
+            None => String::new(),
         };
 
-        if self.ansi {
+        if self.c.ansi {
             println!(
                 "\x1b[1m{:>12} \x1b[1;34m{:x}\x1b[36m{}\x1b[0m{}",
                 op, self.a, p, s
@@ -66,11 +139,40 @@ impl Assets {
             println!("{:>12}: <{:x}> {}{}", op, self.a, p, s);
         }
     }
+    /// Print the changes since the last run and save the index.
+    pub fn save_index(&self) -> R {
+        let (remote, local) = self.index.changes();
+        self.ms("remote", &format!("{} since the last run", remote));
+        self.ms("local", &format!("{} since the last run", local));
+        self.index.save(&state(&self.a, "index"))?;
+        match &self.owners {
+            Some(owners) => owners.save(),
+            None => Ok(()),
+        }
+    }
+    /// The result of the run: an error if any error was printed.
+    pub fn result(&self) -> R {
+        match self.errors.load(Ordering::SeqCst) {
+            0 => Ok(()),
+            n => Err(format!("{} errors", n)),
+        }
+    }
     pub fn err(&self, err: String) {
-        print_err(err, &self.a, self.ansi);
+        self.errors.fetch_add(1, Ordering::SeqCst);
+        print_err(err, &self.a, self.c.ansi);
     }
 }
 
+/// Quote a string for the remote shell.
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+#[test]
+fn test_quote() {
+    assert_eq!(quote("a b"), "'a b'");
+    assert_eq!(quote("it's"), "'it'\\''s'");
+}
+
 pub fn print_err(err: String, a: &Addr, ansi: bool) {
     match ansi {
         true => eprintln!(
@@ -83,6 +185,20 @@ pub fn print_err(err: String, a: &Addr, ansi: bool) {
 
 impl Drop for Assets {
     fn drop(&mut self) {
-        self.ms("DONE", &format!("in {:?}", self.before.elapsed()))
+        self.transfers.join();
+        if self.quiet {
+            return;
+        }
+        let elapsed = self.before.elapsed();
+        let bytes = self.transfers.bytes();
+        self.ms(
+            "DONE",
+            &format!(
+                "in {:?}, {} o transferred at {} o/s",
+                elapsed,
+                bytes.separated_string(),
+                ((bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64).separated_string()
+            ),
+        )
     }
 }
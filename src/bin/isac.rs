@@ -1,7 +1,7 @@
-#![feature(termination_trait_lib, process_exitcode_placeholder)]
-
 use std::fs::File;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
 use threadpool::ThreadPool;
 
@@ -28,6 +28,136 @@ struct Opt {
     /// Disable ANSI char in log.
     #[structopt(long)]
     no_ansi: bool,
+
+    /// Exclude the files that match this gitignore-like pattern.
+    ///
+    /// The patterns of the `.isacignore` files and of the `exclude=` and
+    /// `include=` options of the server list take precedence.
+    #[structopt(long, number_of_values = 1)]
+    exclude: Vec<String>,
+
+    /// Include the files that match this pattern, even if excluded.
+    #[structopt(long, number_of_values = 1)]
+    include: Vec<String>,
+
+    /// How sync resolves files modified on both sides: keep-both, newer, local or remote.
+    ///
+    /// keep-both renames the remote file with a `.conflict` suffix,
+    /// then the two copies are on both sides.
+    #[structopt(long, default_value = "keep-both")]
+    conflict: isac::Conflict,
+
+    /// Upload: skip the directories whose local files are unchanged since the last run.
+    ///
+    /// The remote side of these directories is not listed, so remote
+    /// modifications made by something else than isac are not seen.
+    #[structopt(long)]
+    trust_index: bool,
+
+    /// Save the MD5 of the new and modified local files in the index.
+    #[structopt(long)]
+    hash: bool,
+
+    /// Log each file changed since the last run.
+    #[structopt(long)]
+    changes: bool,
+
+    /// Only upload, download or sync this file or directory, relative to the root (repeatable).
+    ///
+    /// The files out of these paths are neither copied nor removed.
+    #[structopt(long = "path", number_of_values = 1)]
+    paths: Vec<PathBuf>,
+
+    /// Don't go deeper than this number of sub directories, the root is 0.
+    #[structopt(long)]
+    max_depth: Option<usize>,
+
+    /// Number of files transferred at the same time on each host, each on its own connection.
+    #[structopt(long, default_value = "4")]
+    transfers: usize,
+
+    /// The files from this size are split in chunks copied at the same time (K, M or G suffix).
+    #[structopt(long, default_value = "64M", parse(try_from_str = isac::parse_size))]
+    chunk_threshold: u64,
+
+    /// The size of the chunks of the large files, 0 to never split them.
+    #[structopt(long, default_value = "16M", parse(try_from_str = isac::parse_size))]
+    chunk_size: u64,
+
+    /// Limit the transfers of all the hosts together, in bytes per second (K, M or G suffix).
+    ///
+    /// Use the `bwlimit=` option of the server list to limit one host.
+    #[structopt(long, parse(try_from_str = isac::parse_size))]
+    bwlimit: Option<u64>,
+
+    /// On upload, rename on the remote the files moved locally since the last run.
+    ///
+    /// They are found in the index by size and mtime, and by MD5 if the index has it (--hash).
+    #[structopt(long)]
+    detect_moves: bool,
+
+    /// On download, store the identical files of all the hosts once in `.isac/store`, hardlinked in the mirrors.
+    ///
+    /// A file already stored is linked instead of downloaded when its size and remote MD5 match.
+    #[structopt(long)]
+    dedup: bool,
+
+    /// On download, write each run in a new `.isac/<digest>/snapshots/<YYYY-MM-DD-HHMMSS>/` snapshot.
+    ///
+    /// The files unchanged since the last snapshot are hardlinked to it.
+    #[structopt(long)]
+    snapshot: bool,
+
+    /// Encrypt the uploaded files with the `secret` file, and decrypt the downloaded ones.
+    ///
+    /// The remote MD5 and sizes aren't the ones of the files, so `--detect-moves` is ignored
+    /// and `--dedup` never links a file before its download.
+    #[structopt(long)]
+    encrypt: bool,
+
+    /// Encrypt also the names of the remote files (implies --encrypt).
+    #[structopt(long)]
+    encrypt_names: bool,
+
+    /// Keep the owners of the copied files: `numeric` for the same uid and gid, `name` for the
+    /// user and the group of the same name on both sides.
+    ///
+    /// When the local process can't chown, the remote owners are kept in `.isac/<digest>/owners`
+    /// and given back on upload.
+    #[structopt(long)]
+    owner: Option<isac::Owner>,
+
+    /// Before a download or an upload, compare the size of the files to copy with the free space
+    /// of the destination, and `warn` or `refuse` to start when it's too small.
+    #[structopt(long)]
+    check_space: Option<isac::Space>,
+
+    /// Wait for the locks held by another run instead of failing.
+    #[structopt(long)]
+    wait: bool,
+
+    /// Also lock each host with a `.isac-lock` file in its root, against the runs of other
+    /// machines.
+    #[structopt(long)]
+    remote_lock: bool,
+
+    /// Replace the locks of another machine older than this duration (s, m, h or d suffix).
+    ///
+    /// The locks of this machine are replaced as soon as their process is gone.
+    #[structopt(long, default_value = "1d", parse(try_from_str = isac::parse_duration))]
+    stale_lock: u64,
+
+    /// Keep the last snapshot of each of the last N hours.
+    #[structopt(long)]
+    keep_hourly: Option<usize>,
+
+    /// Keep the last snapshot of each of the last N days.
+    #[structopt(long)]
+    keep_daily: Option<usize>,
+
+    /// Keep the last snapshot of each of the last N weeks.
+    #[structopt(long)]
+    keep_weekly: Option<usize>,
 }
 
 #[derive(StructOpt, Debug)]
@@ -36,52 +166,426 @@ enum Command {
     Download,
     /// Upload the files if it no exist on the remote server (from the server list).
     Upload,
+    /// Propagate the creations, modifications and deletions from both sides since the last sync.
+    Sync,
+    /// List the snapshots of each host with their size (from the server list).
+    Snapshots,
     /// List all addrs (from the server list).
     List,
     /// Connect to all servers (from the server list).
     Connect,
-    /// Init the directory: create key and key.pub if not exist and the list file.
+    /// Write the remote tree of each host in a tar file, without a local copy.
+    Archive {
+        /// Compress the archives with `gzip` or `zstd`, or `none`.
+        #[structopt(long, default_value = "none")]
+        compress: isac::Compression,
+        /// Only archive the files changed since the last archive of the host.
+        #[structopt(long)]
+        incremental: bool,
+        /// The directory of the archives.
+        #[structopt(long, default_value = "archive")]
+        output: PathBuf,
+    },
+    /// Download one file from each host.
+    Get {
+        /// The remote file, relative to the root if it isn't absolute.
+        remote: String,
+        /// The local destination, `{host}`, `{user}`, `{digest}` and `{name}` are replaced.
+        ///
+        /// A path ending with `/` or an existing directory receives the file with its name.
+        #[structopt(default_value = "{host}/{name}")]
+        local: String,
+    },
+    /// Upload one file to each host.
+    Put {
+        /// The local file, `{host}`, `{user}` and `{digest}` are replaced.
+        local: String,
+        /// The remote destination, relative to the root if it isn't absolute.
+        ///
+        /// A path ending with `/` or an existing directory receives the file with its name.
+        remote: String,
+    },
+    /// Print the differences between each host and its directory, without modifying anything.
+    ///
+    /// Exit with 1 if there are differences, 2 if a host fails.
+    Status {
+        /// Print `<digest>\t<code>\t<path>` lines, without colors nor summary.
+        #[structopt(long)]
+        porcelain: bool,
+    },
+    /// Compare the size and the MD5 of every file of each host and its directory.
+    ///
+    /// Exit with 1 if there are problems, 2 if a host fails.
+    Verify {
+        /// Compare the content of this percentage of the files, chosen at random.
+        #[structopt(long, default_value = "100")]
+        sample: f64,
+    },
+    /// Upload the local changes of all the hosts as they happen, until it's stopped.
+    Watch {
+        /// Wait until nothing changes during this time before an upload, in milliseconds.
+        #[structopt(long, default_value = "500")]
+        debounce: u64,
+    },
+    /// Run the jobs of the `jobs` file at their time, until it's stopped.
+    Daemon {
+        #[structopt(subcommand)]
+        action: Option<Daemon>,
+    },
+    /// Print the last run of each job of the daemon on each host, like `daemon status`.
+    Jobs,
+    /// Init the directory: create key, key.pub and secret if not exist and the list file.
     Init,
+    /// Copy the tree of one host to other hosts, without a local copy.
+    Mirror {
+        /// The source host: its name, `user@host[:port]`, its full address or its digest.
+        source: String,
+        /// The target hosts.
+        #[structopt(required = true)]
+        targets: Vec<String>,
+    },
+    /// Upload the mirror or a snapshot back to a host, overwriting the remote files.
+    Restore {
+        /// The host: its name, `user@host[:port]`, its full address or its digest.
+        host: String,
+        /// The snapshot to restore, or `latest`. By default the last snapshot, else the mirror.
+        #[structopt(long)]
+        snapshot: Option<String>,
+        /// Restore only this sub directory, relative to the root.
+        #[structopt(long)]
+        path: Option<PathBuf>,
+        /// Restore into this remote root instead of the one of the list.
+        #[structopt(long)]
+        to: Option<String>,
+        /// Keep the remote files that exist instead of overwriting them.
+        #[structopt(long)]
+        keep: bool,
+        /// Remove the remote files that aren't in the local copy.
+        #[structopt(long)]
+        delete: bool,
+        /// Only print what would be done.
+        #[structopt(long)]
+        dry_run: bool,
+        /// Don't ask a confirmation before overwriting or removing files.
+        #[structopt(short, long)]
+        yes: bool,
+    },
 }
 
 fn main() -> finalreturn::R {
     let opt = Opt::from_args();
+    // The stored files are shared by the hosts, they can't have the owners of each one.
+    if opt.owner.is_some() && opt.dedup {
+        return Err("--owner can't be used with --dedup".to_string().into());
+    }
     let l = &opt.list;
-    let ansi = !opt.no_ansi;
-    let f = match opt.cmd {
-        Command::Download { .. } => isac::download,
-        Command::Upload { .. } => isac::upload,
-        Command::List { .. } => isac::list,
-        Command::Connect { .. } => isac::connect,
-        Command::Init { .. } => return init(l, &opt.key),
+    let config = Arc::new(isac::Config {
+        ansi: !opt.no_ansi,
+        key: std::fs::read_to_string("key").ok(),
+        exclude: opt.exclude.clone(),
+        include: opt.include.clone(),
+        conflict: opt.conflict,
+        trust_index: opt.trust_index,
+        hash: opt.hash,
+        changes: opt.changes,
+        paths: opt.paths.clone(),
+        max_depth: opt.max_depth,
+        transfers: opt.transfers,
+        chunk_threshold: opt.chunk_threshold,
+        chunk_size: opt.chunk_size,
+        bwlimit: opt.bwlimit.map(|l| Arc::new(isac::Bucket::new(l))),
+        detect_moves: opt.detect_moves,
+        dedup: opt.dedup,
+        snapshot: opt.snapshot,
+        retention: isac::Retention {
+            hourly: opt.keep_hourly,
+            daily: opt.keep_daily,
+            weekly: opt.keep_weekly,
+        },
+        crypt: match opt.encrypt || opt.encrypt_names {
+            true => Some(Arc::new(isac::Crypt::load(Path::new(isac::SECRET))?)),
+            false => None,
+        },
+        encrypt_names: opt.encrypt_names,
+        owner: opt.owner,
+        check_space: opt.check_space,
+        hooks: isac::hooks(Path::new(isac::HOOKS))?,
+        wait: opt.wait,
+        remote_lock: opt.remote_lock,
+        stale_lock: opt.stale_lock,
+    });
+
+    // The commands that modify a side lock the isac directory, then each host.
+    // The daemon and watch run until they're stopped, they lock only the hosts.
+    let locked = !matches!(
+        opt.cmd,
+        Command::Snapshots
+            | Command::List
+            | Command::Connect
+            | Command::Init
+            | Command::Status { .. }
+            | Command::Verify { .. }
+            | Command::Watch { .. }
+            | Command::Daemon { .. }
+            | Command::Jobs
+    );
+    let hooked = !matches!(opt.cmd, Command::Snapshots | Command::List | Command::Jobs);
+    let _lock = match locked {
+        true => Some(isac::lock_dir(&config)?),
+        false => None,
     };
 
-    let key = std::fs::read_to_string("key").ok();
+    let f: Arc<Run> = match &opt.cmd {
+        Command::Download => Arc::new(isac::download),
+        Command::Upload => Arc::new(isac::upload),
+        Command::Sync => Arc::new(isac::sync),
+        Command::Snapshots => Arc::new(isac::snapshots),
+        Command::List => Arc::new(isac::list),
+        Command::Connect => Arc::new(isac::connect),
+        Command::Archive {
+            compress,
+            incremental,
+            output,
+        } => {
+            let o = isac::Archive {
+                compression: *compress,
+                incremental: *incremental,
+                output: output.clone(),
+            };
+            Arc::new(move |a, c| isac::archive(a, c, &o))
+        }
+        Command::Get { remote, local } | Command::Put { local, remote } => {
+            let s = isac::Single {
+                remote: remote.clone(),
+                local: local.clone(),
+            };
+            match opt.cmd {
+                Command::Get { .. } => Arc::new(move |a, c| isac::get(a, c, &s)),
+                _ => Arc::new(move |a, c| isac::put(a, c, &s)),
+            }
+        }
+        Command::Init => return init(l, &opt.key),
+        Command::Status { porcelain } => {
+            let porcelain = *porcelain;
+            return check(l, config, opt.thread, move |a, c| {
+                isac::status(a, c, porcelain)
+            });
+        }
+        Command::Verify { sample } if !(*sample > 0.0 && *sample <= 100.0) => {
+            return Err(format!("The sample {} must be a percentage", sample).into())
+        }
+        Command::Verify { sample } => {
+            let sample = *sample;
+            return check(l, config, opt.thread, move |a, c| {
+                isac::verify(a, c, sample)
+            });
+        }
+        Command::Watch { debounce } => return watch(l, config, *debounce),
+        Command::Daemon { action: None } => return daemon(l, config),
+        Command::Daemon {
+            action: Some(Daemon::Status),
+        }
+        | Command::Jobs => return Ok(isac::daemon_status(config.ansi)?),
+        Command::Mirror { source, targets } => return mirror(l, source, targets, config),
+        Command::Restore {
+            host,
+            snapshot,
+            path,
+            to,
+            keep,
+            delete,
+            dry_run,
+            yes,
+        } => {
+            let r = isac::Restore {
+                snapshot: snapshot.clone(),
+                path: path.clone(),
+                to: to.clone(),
+                keep: *keep,
+                delete: *delete,
+                dry_run: *dry_run,
+                yes: *yes,
+            };
+            return restore(l, host, config, &r);
+        }
+    };
 
-    let pool = ThreadPool::new(if let Command::List { .. } = opt.cmd {
+    let pool = ThreadPool::new(if let Command::List | Command::Snapshots = opt.cmd {
         1
     } else if opt.thread == 0 {
         4
     } else {
         opt.thread
     });
+    let failed = Arc::new(std::sync::atomic::AtomicU64::new(0));
 
     isac::addr_from_reader(
         File::open(l).map_err(|err| format!("Open {:?} fail because: {}", l, err))?,
     )
     .for_each(|a| {
-        let key = key.clone();
+        let (config, f, failed) = (config.clone(), f.clone(), failed.clone());
         pool.execute(move || {
-            if let Err(e) = f(a.clone(), ansi, key) {
+            let ansi = config.ansi;
+            let r = match locked {
+                true => {
+                    isac::lock_host(&a, &config).and_then(|_locks| f(a.clone(), config.clone()))
+                }
+                false => f(a.clone(), config.clone()),
+            };
+            // The `done` hooks get the result of the host.
+            let r = match hooked {
+                true => isac::done(&config, &a, r),
+                false => r,
+            };
+            if let Err(e) = r {
+                failed.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
                 isac::print_err(e, &a, ansi)
             }
         })
     });
     pool.join();
 
+    hosts_failed(failed.load(std::sync::atomic::Ordering::SeqCst))
+}
+
+/// Fail if hosts failed, their errors are already printed.
+fn hosts_failed(failed: u64) -> finalreturn::R {
+    match failed {
+        0 => Ok(()),
+        n => Err(format!("{} hosts failed", n).into()),
+    }
+}
+
+/// A command run on each host.
+type Run = dyn Fn(isac::Addr, Arc<isac::Config>) -> isac::R + Send + Sync;
+
+#[derive(StructOpt, Debug)]
+enum Daemon {
+    /// Print the last run of each job on each host.
+    Status,
+}
+
+/// The hosts of the list designated by `name`.
+fn find(list: &PathBuf, name: &str) -> Result<Vec<isac::Addr>, String> {
+    let addrs: Vec<isac::Addr> = isac::addr_from_reader(
+        File::open(list).map_err(|err| format!("Open {:?} fail because: {}", list, err))?,
+    )
+    .filter(|a| a.is(name))
+    .collect();
+    match addrs.is_empty() {
+        true => Err(format!("No host {:?} in {:?}", name, list)),
+        false => Ok(addrs),
+    }
+}
+
+/// Mirror the source host to the target hosts.
+fn mirror(
+    list: &PathBuf,
+    source: &str,
+    targets: &[String],
+    config: Arc<isac::Config>,
+) -> finalreturn::R {
+    let mut sources = find(list, source)?;
+    if sources.len() > 1 {
+        return Err(format!("{:?} designates several hosts in {:?}", source, list).into());
+    }
+    let mut addrs = Vec::new();
+    for t in targets.iter() {
+        addrs.extend(find(list, t)?);
+    }
+    let mut locks = Vec::new();
+    for a in sources.iter().chain(addrs.iter()) {
+        locks.extend(isac::lock_host(a, &config)?);
+    }
+    Ok(isac::mirror(sources.remove(0), addrs, config)?)
+}
+
+/// Check all the hosts with `f`, exit with 1 if it finds something and 2 if
+/// a host fails.
+fn check(
+    list: &PathBuf,
+    config: Arc<isac::Config>,
+    thread: usize,
+    f: impl Fn(isac::Addr, Arc<isac::Config>) -> Result<bool, String> + Send + Sync + 'static,
+) -> finalreturn::R {
+    let f = Arc::new(f);
+    let pool = ThreadPool::new(if thread == 0 { 4 } else { thread });
+    let code = Arc::new(std::sync::atomic::AtomicI32::new(0));
+    isac::addr_from_reader(
+        File::open(list).map_err(|err| format!("Open {:?} fail because: {}", list, err))?,
+    )
+    .for_each(|a| {
+        let (config, code, f) = (config.clone(), code.clone(), f.clone());
+        pool.execute(move || {
+            let ansi = config.ansi;
+            let c = match f(a.clone(), config) {
+                Ok(diff) => diff as i32,
+                Err(e) => {
+                    isac::print_err(e, &a, ansi);
+                    2
+                }
+            };
+            code.fetch_max(c, std::sync::atomic::Ordering::SeqCst);
+        })
+    });
+    pool.join();
+    std::process::exit(code.load(std::sync::atomic::Ordering::SeqCst))
+}
+
+/// Watch all the hosts of the list, each one in its own thread.
+fn watch(list: &PathBuf, config: Arc<isac::Config>, debounce: u64) -> finalreturn::R {
+    let threads: Vec<_> = isac::addr_from_reader(
+        File::open(list).map_err(|err| format!("Open {:?} fail because: {}", list, err))?,
+    )
+    .map(|a| {
+        let config = config.clone();
+        std::thread::spawn(move || {
+            let ansi = config.ansi;
+            let r = isac::lock_host(&a, &config).and_then(|_locks| {
+                isac::watch(a.clone(), config.clone(), Duration::from_millis(debounce))
+            });
+            let r = isac::done(&config, &a, r);
+            if let Err(e) = r {
+                isac::print_err(e, &a, ansi)
+            }
+        })
+    })
+    .collect();
+    for t in threads {
+        let _ = t.join();
+    }
     Ok(())
 }
 
+/// Run the jobs on the hosts of the list.
+fn daemon(list: &PathBuf, config: Arc<isac::Config>) -> finalreturn::R {
+    let addrs = isac::addr_from_reader(
+        File::open(list).map_err(|err| format!("Open {:?} fail because: {}", list, err))?,
+    )
+    .collect();
+    Ok(isac::daemon(addrs, config)?)
+}
+
+/// Restore the hosts of the list designated by `host`, one after the other.
+fn restore(
+    list: &PathBuf,
+    host: &str,
+    config: Arc<isac::Config>,
+    r: &isac::Restore,
+) -> finalreturn::R {
+    let mut failed = 0;
+    for a in find(list, host)? {
+        let result = isac::lock_host(&a, &config)
+            .and_then(|_locks| isac::restore(a.clone(), config.clone(), r));
+        let result = isac::done(&config, &a, result);
+        if let Err(e) = result {
+            failed += 1;
+            isac::print_err(e, &a, config.ansi)
+        }
+    }
+    hosts_failed(failed)
+}
+
 // Generate teh SSH key + the list of remote servers.
 fn init(list: &PathBuf, keypath: &str) -> finalreturn::R {
     use osshkeys::{cipher::Cipher, KeyPair, KeyType};
@@ -134,6 +638,14 @@ fn init(list: &PathBuf, keypath: &str) -> finalreturn::R {
         println!("The new key, public part: \n\n{}\n", public);
     }
 
+    if !Path::new(isac::SECRET).exists() {
+        println!(
+            "Generate the encryption secret {:?}, keep a copy of it",
+            isac::SECRET
+        );
+        isac::Crypt::generate(Path::new(isac::SECRET))?;
+    }
+
     Ok(())
 }
 
@@ -145,7 +657,7 @@ mod finalreturn {
     }
     impl std::convert::From<String> for FinalReturn {
         fn from(s: String) -> Self {
-            FinalReturn { s: s }
+            FinalReturn { s }
         }
     }
     impl std::fmt::Debug for FinalReturn {
@@ -154,9 +666,9 @@ mod finalreturn {
         }
     }
     impl std::process::Termination for FinalReturn {
-        fn report(self) -> i32 {
+        fn report(self) -> std::process::ExitCode {
             eprintln!("Error: {}", self.s);
-            return std::process::ExitCode::FAILURE.report();
+            std::process::ExitCode::FAILURE
         }
     }
 }
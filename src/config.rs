@@ -0,0 +1,91 @@
+use super::{Bucket, Conflict, Crypt, Hook, Key, Owner, Retention, Space};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// The options shared by all the hosts of one run.
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    /// Use ANSI char in log.
+    pub ansi: bool,
+    /// The private key, if none, the SSH agent is used.
+    pub key: Key,
+    /// Global patterns of excluded files.
+    pub exclude: Vec<String>,
+    /// Global patterns of included files, they take precedence over `exclude`.
+    pub include: Vec<String>,
+    /// How `sync` resolves the files modified on both sides.
+    pub conflict: Conflict,
+    /// Skip the upload of the sub directories whose local files are unchanged
+    /// since the last run, without listing the remote side.
+    pub trust_index: bool,
+    /// Save the MD5 of the local files in the index.
+    pub hash: bool,
+    /// Log the files changed since the last run.
+    pub changes: bool,
+    /// Compare only these paths, relative to the root.
+    pub paths: Vec<PathBuf>,
+    /// Don't compare the directories deeper than this depth, the root is 0.
+    pub max_depth: Option<usize>,
+    /// The number of files transferred at the same time on each host.
+    pub transfers: usize,
+    /// The files from this size are copied in chunks at the same time.
+    pub chunk_threshold: u64,
+    /// The size of the chunks, 0 to never split the files.
+    pub chunk_size: u64,
+    /// The limit of the transfers of all the hosts together.
+    pub bwlimit: Option<Arc<Bucket>>,
+    /// Rename on the remote the files moved locally since the last run.
+    pub detect_moves: bool,
+    /// Store the downloaded content once for all the hosts, with hardlinks.
+    pub dedup: bool,
+    /// Download each run in a new snapshot of the host directory.
+    pub snapshot: bool,
+    /// The snapshots to keep.
+    pub retention: Retention,
+    /// Encrypt the content of the uploaded files, decrypt the downloaded ones.
+    pub crypt: Option<Arc<Crypt>>,
+    /// Encrypt also the names of the remote files.
+    pub encrypt_names: bool,
+    /// Keep the owners of the files, by uid and gid or by name.
+    pub owner: Option<Owner>,
+    /// Check the free space of the destination before the copies.
+    pub check_space: Option<Space>,
+    /// The local commands run after the copies and at the end of the hosts.
+    pub hooks: Vec<Hook>,
+    /// Wait for the locks held by another run instead of failing.
+    pub wait: bool,
+    /// Lock also the root of the hosts, for the runs of other machines.
+    pub remote_lock: bool,
+    /// The locks of another machine older than this number of seconds are stale.
+    pub stale_lock: u64,
+}
+
+/// Parse a size in bytes, with an optional `K`, `M` or `G` suffix (power of 1024).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (n, unit) = match s.trim().char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s.trim()[..i], c.to_ascii_uppercase()),
+        _ => (s.trim(), 'B'),
+    };
+    let unit: u64 = match unit {
+        'B' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => return Err(format!("Unknown unit in size {:?}, expected K, M or G", s)),
+    };
+    n.parse::<u64>()
+        .map_err(|err| format!("Parse size {:?} fail: {}", s, err))?
+        .checked_mul(unit)
+        .ok_or_else(|| format!("The size {:?} is too big", s))
+}
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("42"), Ok(42));
+    assert_eq!(parse_size("42B"), Ok(42));
+    assert_eq!(parse_size("2k"), Ok(2048));
+    assert_eq!(parse_size("16M"), Ok(16 << 20));
+    assert_eq!(parse_size("1G"), Ok(1 << 30));
+    assert!(parse_size("1T").is_err());
+    assert!(parse_size("M").is_err());
+    assert!(parse_size("-1").is_err());
+}
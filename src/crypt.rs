@@ -0,0 +1,271 @@
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use openssl::symm::{encrypt, Cipher, Crypter, Mode};
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+/// The file of the encryption secret, in the isac directory.
+pub const SECRET: &str = "secret";
+
+/// The bytes added to an encrypted file: the IV before the content, the
+/// MAC after.
+pub const OVERHEAD: u64 = 16 + 32;
+
+/// The keys of the client-side encryption, derived from the secret. The
+/// content is encrypted with AES-256-CTR and a random IV, then
+/// authenticated with HMAC-SHA256. The names are encrypted with an IV
+/// derived from the name, so the same name has always the same remote name.
+pub struct Crypt {
+    content: Vec<u8>,
+    mac: Vec<u8>,
+    names: Vec<u8>,
+}
+
+impl std::fmt::Debug for Crypt {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Crypt")
+    }
+}
+
+fn other<E: std::fmt::Display>(err: E) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    PKey::hmac(key)
+        .and_then(|k| {
+            let mut s = Signer::new(MessageDigest::sha256(), &k)?;
+            s.update(data)?;
+            s.sign_to_vec()
+        })
+        .map_err(|err| format!("HMAC-SHA256 fail: {}", err))
+}
+
+impl Crypt {
+    pub fn new(secret: &[u8]) -> Result<Crypt, String> {
+        Ok(Crypt {
+            content: hmac(secret, b"isac content")?,
+            mac: hmac(secret, b"isac mac")?,
+            names: hmac(secret, b"isac names")?,
+        })
+    }
+    /// Load the secret file, written in hexadecimal.
+    pub fn load(path: &Path) -> Result<Crypt, String> {
+        let s = std::fs::read_to_string(path)
+            .map_err(|err| format!("Read the secret {:?} fail: {}", path, err))?;
+        let s = s.trim();
+        if s.len() < 32 || s.len() % 2 != 0 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!(
+                "The secret {:?} must be at least 16 bytes in hexadecimal",
+                path
+            ));
+        }
+        let secret: Vec<u8> = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect();
+        Crypt::new(&secret)
+    }
+    /// Write a new random secret of 32 bytes, readable only by the user.
+    pub fn generate(path: &Path) -> Result<(), String> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let mut secret = [0u8; 32];
+        rand_bytes(&mut secret).map_err(|err| format!("Generate the secret fail: {}", err))?;
+        let hex: String = secret.iter().map(|b| format!("{:02x}", b)).collect();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut f| writeln!(f, "{}", hex))
+            .map_err(|err| format!("Write the secret {:?} fail: {}", path, err))
+    }
+
+    /// Encrypt `src` into `dst`, return the bytes written.
+    pub fn encrypt<S: Read, D: Write>(&self, mut src: S, dst: &mut D) -> io::Result<u64> {
+        let mut iv = [0u8; 16];
+        rand_bytes(&mut iv).map_err(other)?;
+        let mut c = Crypter::new(
+            Cipher::aes_256_ctr(),
+            Mode::Encrypt,
+            &self.content,
+            Some(&iv),
+        )
+        .map_err(other)?;
+        let key = PKey::hmac(&self.mac).map_err(other)?;
+        let mut mac = Signer::new(MessageDigest::sha256(), &key).map_err(other)?;
+        mac.update(&iv).map_err(other)?;
+        dst.write_all(&iv)?;
+
+        let mut buf = vec![0u8; 1 << 16];
+        let mut out = vec![0u8; buf.len() + 16];
+        let mut written = OVERHEAD;
+        loop {
+            let n = match src.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            let k = c.update(&buf[..n], &mut out).map_err(other)?;
+            mac.update(&out[..k]).map_err(other)?;
+            dst.write_all(&out[..k])?;
+            written += k as u64;
+        }
+        let k = c.finalize(&mut out).map_err(other)?;
+        mac.update(&out[..k]).map_err(other)?;
+        dst.write_all(&out[..k])?;
+        dst.write_all(&mac.sign_to_vec().map_err(other)?)?;
+        Ok(written + k as u64)
+    }
+
+    /// Decrypt `src` into `dst`, return the bytes written. The MAC is
+    /// checked at the end: on error, `dst` must be thrown away.
+    pub fn decrypt<S: Read, D: Write>(&self, mut src: S, dst: &mut D) -> io::Result<u64> {
+        let mut iv = [0u8; 16];
+        src.read_exact(&mut iv)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "not an encrypted file"))?;
+        let mut c = Crypter::new(
+            Cipher::aes_256_ctr(),
+            Mode::Decrypt,
+            &self.content,
+            Some(&iv),
+        )
+        .map_err(other)?;
+        let key = PKey::hmac(&self.mac).map_err(other)?;
+        let mut mac = Signer::new(MessageDigest::sha256(), &key).map_err(other)?;
+        mac.update(&iv).map_err(other)?;
+
+        // The last 32 bytes read are the MAC, not the content.
+        let mut data = Vec::with_capacity((1 << 16) + 32);
+        let mut out = vec![0u8; (1 << 16) + 32 + 16];
+        let mut written = 0;
+        loop {
+            let len = data.len();
+            data.resize(len + (1 << 16), 0);
+            let n = loop {
+                match src.read(&mut data[len..]) {
+                    Ok(n) => break n,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                }
+            };
+            data.truncate(len + n);
+            if n == 0 {
+                break;
+            }
+            if data.len() > 32 {
+                let end = data.len() - 32;
+                mac.update(&data[..end]).map_err(other)?;
+                let k = c.update(&data[..end], &mut out).map_err(other)?;
+                dst.write_all(&out[..k])?;
+                written += k as u64;
+                data.drain(..end);
+            }
+        }
+        let k = c.finalize(&mut out).map_err(other)?;
+        dst.write_all(&out[..k])?;
+        let tag = mac.sign_to_vec().map_err(other)?;
+        match data.len() == tag.len() && memcmp::eq(&data, &tag) {
+            true => Ok(written + k as u64),
+            false => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the MAC doesn't match, wrong secret or modified file",
+            )),
+        }
+    }
+
+    /// The encrypted name of a file, in URL safe base64.
+    pub fn encrypt_name(&self, name: &Path) -> Result<PathBuf, String> {
+        let plain = name.as_os_str().as_bytes();
+        let mut data = hmac(&self.names, plain)?;
+        data.truncate(16);
+        let c = encrypt(Cipher::aes_256_ctr(), &self.names, Some(&data), plain)
+            .map_err(|err| format!("Encrypt the name {:?} fail: {}", name, err))?;
+        data.extend_from_slice(&c);
+        Ok(PathBuf::from(base64(&data)))
+    }
+    /// The name of a file from its encrypted name, none if it isn't one.
+    pub fn decrypt_name(&self, name: &Path) -> Option<PathBuf> {
+        let data = unbase64(name.to_str()?)?;
+        if data.len() < 16 {
+            return None;
+        }
+        let (iv, c) = data.split_at(16);
+        let plain = encrypt(Cipher::aes_256_ctr(), &self.names, Some(iv), c).ok()?;
+        match memcmp::eq(&hmac(&self.names, &plain).ok()?[..16], iv) {
+            true => Some(PathBuf::from(OsString::from_vec(plain))),
+            false => None,
+        }
+    }
+}
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Encode in URL safe base64 without padding.
+fn base64(data: &[u8]) -> String {
+    let mut s = String::with_capacity((data.len() * 4).div_ceil(3));
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            s.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char);
+        }
+    }
+    s
+}
+fn unbase64(s: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(s.len() * 3 / 4);
+    for chunk in s.as_bytes().chunks(4) {
+        if chunk.len() < 2 {
+            return None;
+        }
+        let mut n = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let v = BASE64.iter().position(|b| b == c)? as u32;
+            n |= v << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            data.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(data)
+}
+#[test]
+fn test_crypt() {
+    for len in [0, 1, 2, 3, 4, 5, 200].iter() {
+        let data: Vec<u8> = (0..*len).map(|i| i as u8).collect();
+        assert_eq!(unbase64(&base64(&data)), Some(data));
+    }
+    assert_eq!(base64(b"isac"), "aXNhYw");
+
+    let c = Crypt::new(b"0123456789abcdef").unwrap();
+    let plain: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let mut enc = Vec::new();
+    assert_eq!(c.encrypt(&plain[..], &mut enc).unwrap(), enc.len() as u64);
+    assert_eq!(enc.len() as u64, plain.len() as u64 + OVERHEAD);
+    assert_ne!(&enc[16..116], &plain[..100]);
+    let mut dec = Vec::new();
+    assert_eq!(c.decrypt(&enc[..], &mut dec).unwrap(), plain.len() as u64);
+    assert_eq!(dec, plain);
+
+    enc[1000] ^= 1;
+    assert!(c.decrypt(&enc[..], &mut Vec::new()).is_err());
+    assert!(Crypt::new(b"other secret")
+        .unwrap()
+        .decrypt(&enc[..], &mut Vec::new())
+        .is_err());
+
+    let name = c.encrypt_name(Path::new("photo.jpg")).unwrap();
+    assert_eq!(name, c.encrypt_name(Path::new("photo.jpg")).unwrap());
+    assert_ne!(name, c.encrypt_name(Path::new("photo.png")).unwrap());
+    assert_eq!(c.decrypt_name(&name), Some(PathBuf::from("photo.jpg")));
+    assert_eq!(c.decrypt_name(Path::new("photo.jpg")), None);
+}
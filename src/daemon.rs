@@ -0,0 +1,474 @@
+use super::{done, download, lock_host, snapshot, sync, upload, Addr, Config, R, STATE};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs::{create_dir_all, rename, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+
+/// The file of the jobs, in the isac directory.
+pub const JOBS: &str = "jobs";
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// The path of the status of the jobs.
+fn status_path() -> PathBuf {
+    PathBuf::from(STATE).join("daemon")
+}
+
+/* SCHEDULE */
+
+/// A cron expression: minute, hour, day of month, month and day of week,
+/// in UTC. Each field is a set of bits.
+#[derive(Debug, PartialEq)]
+struct Cron {
+    fields: [u64; 5],
+    /// The day of month and the day of week are restricted, a day matches
+    /// if one of them matches. Like in cron, a field starting with `*`, as
+    /// `*/2`, doesn't restrict the days.
+    either: bool,
+}
+
+const RANGES: [(u64, u64); 5] = [(0, 59), (0, 23), (1, 31), (1, 12), (0, 7)];
+
+impl Cron {
+    fn parse(fields: &[&str]) -> Result<Cron, String> {
+        if fields.len() != 5 {
+            return Err(format!(
+                "A cron expression has 5 fields: {:?}",
+                fields.join(" ")
+            ));
+        }
+        let mut bits = [0u64; 5];
+        for (i, f) in fields.iter().enumerate() {
+            bits[i] = Cron::field(f, RANGES[i]).ok_or_else(|| format!("Bad cron field {:?}", f))?;
+        }
+        // Sunday is 0 or 7.
+        if bits[4] & 1 << 7 != 0 {
+            bits[4] |= 1;
+        }
+        Ok(Cron {
+            fields: bits,
+            either: !fields[2].starts_with('*') && !fields[4].starts_with('*'),
+        })
+    }
+    /// Parse one field: `*`, `a`, `a-b`, with `/step`, separated by commas.
+    fn field(f: &str, (min, max): (u64, u64)) -> Option<u64> {
+        let mut bits = 0;
+        for part in f.split(',') {
+            let (range, step) = match part.find('/') {
+                Some(i) => (&part[..i], part[i + 1..].parse::<u64>().ok()?),
+                None => (part, 1),
+            };
+            let (a, b) = match range {
+                "*" => (min, max),
+                _ => match range.find('-') {
+                    Some(i) => (range[..i].parse().ok()?, range[i + 1..].parse().ok()?),
+                    None => {
+                        let a = range.parse().ok()?;
+                        (a, if step > 1 { max } else { a })
+                    }
+                },
+            };
+            if a < min || b > max || a > b || step == 0 {
+                return None;
+            }
+            for v in (a..=b).step_by(step as usize) {
+                bits |= 1 << v;
+            }
+        }
+        Some(bits)
+    }
+    fn matches(&self, secs: u64) -> bool {
+        let days = secs / 86400;
+        let (_, month, day) = snapshot::civil_from_days(days as i64);
+        let s = secs % 86400;
+        // Thursday 1970-01-01 is the day 0.
+        let values = [
+            s / 60 % 60,
+            s / 3600,
+            day as u64,
+            month as u64,
+            (days + 4) % 7,
+        ];
+        let m = |i: usize| self.fields[i] & 1 << values[i] != 0;
+        let day = match self.either {
+            true => m(2) || m(4),
+            false => m(2) && m(4),
+        };
+        m(0) && m(1) && m(3) && day
+    }
+}
+
+/// Parse a duration with a `s`, `m`, `h` or `d` suffix, in seconds.
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+    let (n, unit) = s.split_at(s.len().saturating_sub(1));
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(format!("Unknown unit in {:?}, expected s, m, h or d", s)),
+    };
+    match n.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(n * unit),
+        _ => Err(format!("Bad duration {:?}", s)),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Schedule {
+    /// Run again this number of seconds after the last start.
+    Every(u64),
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// Check if a job started at `last` must run at `now`.
+    fn due(&self, last: Option<u64>, now: u64) -> bool {
+        match self {
+            Schedule::Every(d) => last.is_none_or(|l| now >= l + d),
+            Schedule::Cron(c) => c.matches(now) && last.is_none_or(|l| l / 60 != now / 60),
+        }
+    }
+}
+
+/* JOBS */
+
+/// A job of the jobs file, one by line:
+/// `<name> every <duration>|<cron expression> <command> [hosts...]`.
+#[derive(Debug, PartialEq)]
+pub struct Job {
+    name: String,
+    schedule: Schedule,
+    /// download, upload, sync or snapshot.
+    command: String,
+    /// The hosts, like for `restore`, all the hosts if empty.
+    hosts: Vec<String>,
+}
+
+impl Job {
+    fn parse(line: &str) -> Result<Job, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (schedule, rest) = match words.get(1) {
+            Some(&"every") if words.len() > 2 => {
+                (Schedule::Every(parse_duration(words[2])?), &words[3..])
+            }
+            _ if words.len() > 6 => (Schedule::Cron(Cron::parse(&words[1..6])?), &words[6..]),
+            _ => return Err(format!("Bad job {:?}", line)),
+        };
+        let command = match rest.first() {
+            Some(c) if ["download", "upload", "sync", "snapshot"].contains(c) => c.to_string(),
+            _ => {
+                return Err(format!(
+                    "The job {:?} needs a command: download, upload, sync or snapshot",
+                    words[0]
+                ))
+            }
+        };
+        Ok(Job {
+            name: words[0].to_string(),
+            schedule,
+            command,
+            hosts: rest[1..].iter().map(|h| h.to_string()).collect(),
+        })
+    }
+    fn selects(&self, a: &Addr) -> bool {
+        self.hosts.is_empty() || self.hosts.iter().any(|h| a.is(h))
+    }
+}
+
+/// Read the jobs file, the empty lines and the lines beginning with `#` are
+/// ignored.
+pub fn jobs(path: &Path) -> Result<Vec<Job>, String> {
+    std::fs::read_to_string(path)
+        .map_err(|err| format!("Read {:?} fail: {}", path, err))?
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(Job::parse)
+        .collect()
+}
+#[test]
+fn test_jobs() {
+    assert_eq!(parse_duration("90s"), Ok(90));
+    assert_eq!(parse_duration("15m"), Ok(900));
+    assert!(parse_duration("15").is_err());
+    assert!(parse_duration("0h").is_err());
+
+    let j = Job::parse("often every 2h download web db").unwrap();
+    assert_eq!(j.schedule, Schedule::Every(7200));
+    assert_eq!(j.command, "download");
+    assert_eq!(j.hosts, vec!["web", "db"]);
+    assert!(!j.schedule.due(Some(1000), 1000 + 7199));
+    assert!(j.schedule.due(Some(1000), 1000 + 7200));
+    assert!(j.schedule.due(None, 0));
+
+    let j = Job::parse("nightly 30 3 * * 1-5 snapshot").unwrap();
+    assert!(j.hosts.is_empty());
+    // 2020-09-14 was a Monday, 2020-09-13 a Sunday.
+    let monday = 1600041600;
+    assert!(j.schedule.due(None, monday + 3 * 3600 + 30 * 60 + 59));
+    assert!(!j.schedule.due(
+        Some(monday + 3 * 3600 + 30 * 60),
+        monday + 3 * 3600 + 30 * 60 + 1
+    ));
+    assert!(!j.schedule.due(None, monday + 3 * 3600 + 31 * 60));
+    assert!(!j.schedule.due(None, monday - 86400 + 3 * 3600 + 30 * 60));
+
+    let c = Cron::parse(&["*/15", "*", "1", "*", "0"]).unwrap();
+    assert_eq!(c.fields[0], 1 | 1 << 15 | 1 << 30 | 1 << 45);
+    assert!(c.either);
+    assert!(c.matches(1600000200));
+    assert!(!c.matches(1600000200 + 60));
+    // The odd days that are Mondays.
+    let c = Cron::parse(&["0", "0", "*/2", "*", "1"]).unwrap();
+    assert!(!c.either);
+    assert!(!c.matches(monday));
+    assert!(c.matches(monday + 7 * 86400));
+    assert!(!c.matches(monday + 86400));
+    assert!(Cron::parse(&["60", "*", "*", "*", "*"]).is_err());
+    assert!(Job::parse("bad every 1h shutdown").is_err());
+}
+
+/* STATUS */
+
+/// The last run of a job on a host.
+#[derive(Debug, Clone, PartialEq)]
+struct Run {
+    host: String,
+    start: u64,
+    /// None while running.
+    end: Option<u64>,
+    /// The error of the run, if any.
+    error: Option<String>,
+}
+
+/// The last runs by job and host digest.
+#[derive(Debug, Default, PartialEq)]
+struct Status(BTreeMap<(String, String), Run>);
+
+impl Status {
+    fn load(path: &Path) -> Result<Status, String> {
+        let s = match std::fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Status::default()),
+            Err(err) => return Err(format!("Read {:?} fail: {}", path, err)),
+        };
+        let mut status = Status::default();
+        for line in s.lines().filter(|l| !l.is_empty()) {
+            let f: Vec<&str> = line.splitn(6, '\t').collect();
+            if f.len() != 6 {
+                return Err(format!("Bad line in {:?}: {:?}", path, line));
+            }
+            let n = |s: &str| {
+                s.parse::<u64>()
+                    .map_err(|err| format!("Bad line in {:?}: {}", path, err))
+            };
+            let run = Run {
+                host: f[2].to_string(),
+                start: n(f[3])?,
+                end: match f[4] {
+                    "-" => None,
+                    end => Some(n(end)?),
+                },
+                error: match f[5] {
+                    "" => None,
+                    err => Some(err.to_string()),
+                },
+            };
+            status.0.insert((f[0].to_string(), f[1].to_string()), run);
+        }
+        Ok(status)
+    }
+    fn save(&self, path: &Path) -> R {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir).map_err(|err| format!("Create {:?} fail: {}", dir, err))?;
+        }
+        let tmp = path.with_extension("tmp");
+        File::create(&tmp)
+            .and_then(|mut f| write!(f, "{}", self))
+            .map_err(|err| format!("Write {:?} fail: {}", tmp, err))?;
+        rename(&tmp, path).map_err(|err| format!("Move {:?} fail: {}", path, err))
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for ((job, digest), r) in self.0.iter() {
+            writeln!(
+                f,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                job,
+                digest,
+                r.host,
+                r.start,
+                r.end.map_or("-".to_string(), |e| e.to_string()),
+                r.error
+                    .as_deref()
+                    .unwrap_or("")
+                    .replace(&['\t', '\n'][..], " ")
+            )?;
+        }
+        Ok(())
+    }
+}
+#[test]
+fn test_status() {
+    let mut s = Status::default();
+    let run = Run {
+        host: "user@host:22/root".to_string(),
+        start: 10,
+        end: Some(20),
+        error: Some("Connect fail".to_string()),
+    };
+    s.0.insert(("nightly".to_string(), "abc".to_string()), run);
+    let running = Run {
+        host: "user@other:22/".to_string(),
+        start: 30,
+        end: None,
+        error: None,
+    };
+    s.0.insert(("often".to_string(), "def".to_string()), running);
+
+    let path = std::env::temp_dir().join("isac-test-daemon-status");
+    s.save(&path).unwrap();
+    assert_eq!(Status::load(&path).unwrap(), s);
+    std::fs::remove_file(&path).unwrap();
+}
+
+/* DAEMON */
+
+fn print(ansi: bool, action: &str, job: &str, a: &Addr, detail: &str) {
+    if ansi {
+        println!(
+            "\x1b[1m{:>12} \x1b[32m{} \x1b[1;34m{:x}\x1b[0m {}",
+            action, job, a, detail
+        );
+    } else {
+        println!("{:>12}: {} <{:x}> {}", action, job, a, detail);
+    }
+}
+
+/// Run the jobs of the jobs file on the hosts at their time, until it's
+/// stopped. A host runs one job at a time, a job due while another runs on
+/// the host is skipped. Each run holds the lock of its host.
+pub fn daemon(addrs: Vec<Addr>, c: Arc<Config>) -> R {
+    let jobs = jobs(Path::new(JOBS))?;
+    let path = status_path();
+    let status = Arc::new(Mutex::new(Status::load(&path)?));
+    let running: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+    let mut skipped = HashSet::new();
+
+    // The runs not finished were stopped with the daemon.
+    for r in status
+        .lock()
+        .unwrap()
+        .0
+        .values_mut()
+        .filter(|r| r.end.is_none())
+    {
+        r.end = Some(r.start);
+        r.error = Some("Stopped with the daemon".to_string());
+    }
+
+    loop {
+        let now = now();
+        for job in jobs.iter() {
+            for a in addrs.iter().filter(|a| job.selects(a)) {
+                let key = (job.name.clone(), a.digest.clone());
+                let last = status.lock().unwrap().0.get(&key).map(|r| r.start);
+                if !job.schedule.due(last, now) {
+                    continue;
+                }
+                if !running.lock().unwrap().insert(a.digest.clone()) {
+                    if skipped.insert(key) {
+                        print(c.ansi, "skip", &job.name, a, "another job is running");
+                    }
+                    continue;
+                }
+                skipped.remove(&key);
+
+                print(c.ansi, "start", &job.name, a, &job.command);
+                {
+                    let mut s = status.lock().unwrap();
+                    s.0.insert(
+                        key.clone(),
+                        Run {
+                            host: a.to_string(),
+                            start: now,
+                            end: None,
+                            error: None,
+                        },
+                    );
+                    s.save(&path)?;
+                }
+
+                let (a, status, running, path) =
+                    (a.clone(), status.clone(), running.clone(), path.clone());
+                let mut config = (*c).clone();
+                let f = match job.command.as_str() {
+                    "upload" => upload,
+                    "sync" => sync,
+                    "snapshot" => {
+                        config.snapshot = true;
+                        download
+                    }
+                    _ => download,
+                };
+                let (name, ansi) = (job.name.clone(), c.ansi);
+                std::thread::spawn(move || {
+                    let config = Arc::new(config);
+                    let r = lock_host(&a, &config).and_then(|_locks| f(a.clone(), config.clone()));
+                    let r = done(&config, &a, r);
+                    let mut s = status.lock().unwrap();
+                    if let Some(run) = s.0.get_mut(&key) {
+                        run.end = Some(self::now());
+                        run.error = r.err();
+                        let detail = match &run.error {
+                            Some(err) => format!("error: {}", err),
+                            None => "ok".to_string(),
+                        };
+                        print(ansi, "end", &name, &a, &detail);
+                    }
+                    if let Err(err) = s.save(&path) {
+                        eprintln!("{}", err);
+                    }
+                    running.lock().unwrap().remove(&a.digest);
+                });
+            }
+        }
+        sleep(Duration::from_secs(1));
+    }
+}
+
+/// Print the last run of each job on each host.
+pub fn daemon_status(ansi: bool) -> R {
+    let status = Status::load(&status_path())?;
+    for ((job, _), r) in status.0.iter() {
+        let state = match (r.end, &r.error) {
+            (None, _) => "running".to_string(),
+            (Some(_), None) => "ok".to_string(),
+            (Some(_), Some(err)) => format!("error: {}", err),
+        };
+        let took = r.end.map_or(String::new(), |e| {
+            format!(" in {}s", e.saturating_sub(r.start))
+        });
+        let detail = format!("{}{} {}", snapshot::name(r.start), took, state);
+        if ansi {
+            println!(
+                "\x1b[1m{:>12} \x1b[32m{} \x1b[1;34m{}\x1b[0m {}",
+                "job", job, r.host, detail
+            );
+        } else {
+            println!("{:>12}: {} <{}> {}", "job", job, r.host, detail);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,266 @@
+use super::{Addr, Config, LOCK};
+use std::path::Path;
+use std::sync::Arc;
+
+/// The name of the files that contain patterns for their directory.
+pub const IGNORE: &str = ".isacignore";
+
+/// One gitignore-like pattern.
+#[derive(Debug, Clone, PartialEq)]
+struct Pattern {
+    glob: String,
+    /// The pattern start with `!`, it re-include the file.
+    negate: bool,
+    /// The pattern end with `/`, it match only directories.
+    dir_only: bool,
+    /// The pattern contains a `/`, it's relative to the directory of
+    /// the pattern instead of matching the file name at any level.
+    anchored: bool,
+}
+
+impl Pattern {
+    fn parse(line: &str) -> Option<Pattern> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negate, line) = match line.strip_prefix('!') {
+            Some(l) => (true, l),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(l) => (true, l),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.trim_start_matches('/');
+        if line.is_empty() {
+            return None;
+        }
+
+        Some(Pattern {
+            glob: line.to_string(),
+            negate,
+            dir_only,
+            anchored,
+        })
+    }
+    /// The path is relative to the directory of the pattern.
+    fn matches(&self, path: &str, dir: bool) -> bool {
+        if self.dir_only && !dir {
+            return false;
+        }
+        let text = match self.anchored {
+            true => path,
+            false => path.rsplit('/').next().unwrap_or(path),
+        };
+        glob(self.glob.as_bytes(), text.as_bytes())
+    }
+}
+
+/// Check if a gitignore-like pattern matches a file, by its path relative
+/// to the root.
+pub fn matches(pattern: &str, path: &str, dir: bool) -> bool {
+    Pattern::parse(pattern).is_some_and(|p| p.matches(path, dir) != p.negate)
+}
+
+/// Match a text with a glob: `*`, `**`, `?`, `[a-z]`, `[!a-z]` and `\` escape.
+fn glob(p: &[u8], t: &[u8]) -> bool {
+    match p.first() {
+        None => t.is_empty(),
+        Some(b'*') if p.get(1) == Some(&b'*') => match p.get(2) {
+            None => true,
+            Some(b'/') => (0..=t.len())
+                .filter(|&i| i == 0 || t[i - 1] == b'/')
+                .any(|i| glob(&p[3..], &t[i..])),
+            Some(_) => (0..=t.len()).any(|i| glob(&p[2..], &t[i..])),
+        },
+        Some(b'*') => (0..=t.len())
+            .take_while(|&i| i == 0 || t[i - 1] != b'/')
+            .any(|i| glob(&p[1..], &t[i..])),
+        Some(b'?') => !t.is_empty() && t[0] != b'/' && glob(&p[1..], &t[1..]),
+        Some(b'[') => match class(&p[1..]) {
+            Some((len, negate, set)) => {
+                !t.is_empty()
+                    && t[0] != b'/'
+                    && set.iter().any(|&(a, b)| a <= t[0] && t[0] <= b) != negate
+                    && glob(&p[len + 1..], &t[1..])
+            }
+            None => t.first() == Some(&b'[') && glob(&p[1..], &t[1..]),
+        },
+        Some(b'\\') if p.len() > 1 => t.first() == Some(&p[1]) && glob(&p[2..], &t[1..]),
+        Some(&c) => t.first() == Some(&c) && glob(&p[1..], &t[1..]),
+    }
+}
+
+/// The ranges of a class, the single chars are ranges of one char.
+type Ranges = Vec<(u8, u8)>;
+
+/// Parse a class after the `[`, return the length with the `]`, if it's
+/// negated and the ranges.
+fn class(p: &[u8]) -> Option<(usize, bool, Ranges)> {
+    let negate = p.first() == Some(&b'!') || p.first() == Some(&b'^');
+    let mut i = if negate { 1 } else { 0 };
+    let mut set = Vec::new();
+    loop {
+        let c = *p.get(i)?;
+        if c == b']' && !set.is_empty() {
+            return Some((i + 1, negate, set));
+        }
+        match (p.get(i + 1), p.get(i + 2)) {
+            (Some(b'-'), Some(&e)) if e != b']' => {
+                set.push((c, e));
+                i += 3;
+            }
+            _ => {
+                set.push((c, c));
+                i += 1;
+            }
+        }
+    }
+}
+#[test]
+fn test_glob() {
+    assert!(glob(b"*.log", b"a.log"));
+    assert!(!glob(b"*.log", b"dir/a.log"));
+    assert!(glob(b"**/a.log", b"a.log"));
+    assert!(glob(b"**/a.log", b"x/y/a.log"));
+    assert!(glob(b"x/**", b"x/y/a.log"));
+    assert!(glob(b"x/**/z", b"x/z"));
+    assert!(glob(b"x/**/z", b"x/a/b/z"));
+    assert!(!glob(b"x/**/z", b"x/az"));
+    assert!(glob(b"?.[ch]", b"a.c"));
+    assert!(!glob(b"?.[!ch]", b"a.c"));
+    assert!(glob(b"[a-c]x", b"bx"));
+    assert!(!glob(b"[a-c]x", b"dx"));
+    assert!(glob(b"\\*", b"*"));
+    assert!(!glob(b"\\*", b"a"));
+}
+
+/// The filter for one directory: its patterns and the ones of its parents.
+#[derive(Debug)]
+pub struct Filter {
+    /// The directory, relative to the root, with `/` separator.
+    dir: String,
+    patterns: Vec<Pattern>,
+    parent: Option<Arc<Filter>>,
+}
+
+impl Filter {
+    /// The root filter, from the global patterns then the host patterns. The
+    /// remote lock is always excluded.
+    pub fn new(c: &Config, a: &Addr) -> Arc<Filter> {
+        let global = Arc::new(Filter {
+            dir: String::new(),
+            patterns: c
+                .exclude
+                .iter()
+                .map(|p| p.to_string())
+                .chain(c.include.iter().map(|p| format!("!{}", p)))
+                .chain(std::iter::once(format!("/{}", LOCK)))
+                .filter_map(|p| Pattern::parse(&p))
+                .collect(),
+            parent: None,
+        });
+        Arc::new(Filter {
+            dir: String::new(),
+            patterns: a
+                .options
+                .iter()
+                .filter_map(|(k, v)| match k.as_str() {
+                    "exclude" => Pattern::parse(v),
+                    "include" => Pattern::parse(&format!("!{}", v)),
+                    _ => None,
+                })
+                .collect(),
+            parent: Some(global),
+        })
+    }
+    /// The filter of the sub directory `name`.
+    pub fn enter(self: &Arc<Self>, name: &Path) -> Arc<Filter> {
+        let name = name.to_string_lossy();
+        Arc::new(Filter {
+            dir: match self.dir.is_empty() {
+                true => name.to_string(),
+                false => format!("{}/{}", self.dir, name),
+            },
+            patterns: Vec::new(),
+            parent: Some(self.clone()),
+        })
+    }
+    /// This directory, relative to the root.
+    pub fn dir(&self) -> &str {
+        &self.dir
+    }
+    /// The path of the file `name` of this directory, relative to the root.
+    pub fn path(&self, name: &Path) -> String {
+        let name = name.to_string_lossy();
+        match self.dir.is_empty() {
+            true => name.to_string(),
+            false => format!("{}/{}", self.dir, name),
+        }
+    }
+    /// Add the patterns of an ignore file of this directory.
+    pub fn with(self: &Arc<Self>, content: &str) -> Arc<Filter> {
+        let patterns: Vec<Pattern> = content.lines().filter_map(Pattern::parse).collect();
+        if patterns.is_empty() {
+            return self.clone();
+        }
+        Arc::new(Filter {
+            dir: self.dir.clone(),
+            patterns,
+            parent: Some(self.clone()),
+        })
+    }
+    /// Check if the file `name` of this directory is excluded. The deepest
+    /// patterns take precedence, and the last matching pattern of a level wins.
+    pub fn excluded(&self, name: &Path, dir: bool) -> bool {
+        let path = self.path(name);
+        let mut level = Some(self);
+        while let Some(f) = level {
+            let relative = match f.dir.is_empty() {
+                true => &path[..],
+                false => &path[f.dir.len() + 1..],
+            };
+            if let Some(p) = f.patterns.iter().rev().find(|p| p.matches(relative, dir)) {
+                return !p.negate;
+            }
+            level = f.parent.as_deref();
+        }
+        false
+    }
+}
+#[test]
+fn test_filter() {
+    use std::convert::TryFrom;
+
+    let c = Config {
+        exclude: vec!["*.log".to_string(), "/cache/".to_string()],
+        include: vec!["keep.log".to_string()],
+        ..Config::default()
+    };
+    let a = Addr::try_from("u@h/r exclude=*.tmp include=cache.tmp").unwrap();
+    let root = Filter::new(&c, &a);
+
+    assert!(root.excluded(Path::new("a.log"), false));
+    assert!(!root.excluded(Path::new("keep.log"), false));
+    assert!(root.excluded(Path::new("cache"), true));
+    assert!(!root.excluded(Path::new("cache"), false));
+    assert!(root.excluded(Path::new("a.tmp"), false));
+    assert!(!root.excluded(Path::new("cache.tmp"), false));
+
+    let sub = root.enter(Path::new("sub"));
+    assert!(sub.excluded(Path::new("a.log"), false));
+    assert!(!sub.excluded(Path::new("cache"), true));
+
+    let sub = sub.with("# comment\n!*.log\n/target/\n");
+    assert!(!sub.excluded(Path::new("a.log"), false));
+    assert!(sub.excluded(Path::new("target"), true));
+    assert!(!sub
+        .enter(Path::new("x"))
+        .excluded(Path::new("target"), true));
+    assert!(sub
+        .enter(Path::new("x"))
+        .excluded(Path::new("a.tmp"), false));
+}
@@ -0,0 +1,187 @@
+use super::{matches, Addr, Assets, Config, R};
+use ssh2::ExtendedData;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::Command;
+
+/// Run the `pre` or `post` commands of the host, in the list order, on the
+/// SSH session of the assets. Their output is printed line by line, and the
+/// first that fails stops the others.
+pub fn remote_hook(a: &Assets, name: &str) -> R {
+    for cmd in a.a.option(name) {
+        a.ms(name, cmd);
+        let mut ch = a
+            .session
+            .channel_session()
+            .map_err(|err| format!("Open channel fail for {}: {}", a.a, err))?;
+        // The standard error is merged, it can't fill the window unread.
+        ch.handle_extended_data(ExtendedData::Merge)
+            .and_then(|_| ch.exec(cmd))
+            .map_err(|err| format!("Run the {} hook {:?} fail: {}", name, cmd, err))?;
+        for line in BufReader::new(&mut ch).lines() {
+            let line = line.map_err(|err| {
+                format!(
+                    "Read the output of the {} hook {:?} fail: {}",
+                    name, cmd, err
+                )
+            })?;
+            a.ms(name, &line);
+        }
+        ch.wait_close()
+            .map_err(|err| format!("Close the channel of {:?} fail: {}", cmd, err))?;
+        match ch.exit_status() {
+            Ok(0) => {}
+            Ok(code) => return Err(format!("The {} hook {:?} exit with {}", name, cmd, code)),
+            Err(err) => return Err(format!("Get the exit status of {:?} fail: {}", cmd, err)),
+        }
+    }
+    Ok(())
+}
+
+/// The file of the local hooks, in the isac directory.
+pub const HOOKS: &str = "hooks";
+
+/// A local hook of the hooks file, one by line: `<event> <selector>
+/// <command>`. The event is `download` or `upload` for each copied file
+/// whose path matches the selector, a gitignore-like pattern, or `done` for
+/// each host designated by the selector, `*` for all, at its end.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hook {
+    event: String,
+    selector: String,
+    command: String,
+}
+
+impl Hook {
+    fn parse(line: &str) -> Result<Hook, String> {
+        let mut words = line.splitn(3, char::is_whitespace);
+        match (words.next(), words.next(), words.next().map(str::trim)) {
+            (Some(e), Some(s), Some(c)) if !s.is_empty() && !c.is_empty() => {
+                match ["download", "upload", "done"].contains(&e) {
+                    true => Ok(Hook {
+                        event: e.to_string(),
+                        selector: s.to_string(),
+                        command: c.to_string(),
+                    }),
+                    false => Err(format!(
+                        "Unknown hook event {:?}, expected download, upload or done",
+                        e
+                    )),
+                }
+            }
+            _ => Err(format!("Bad hook {:?}", line)),
+        }
+    }
+    fn selects(&self, event: &str, a: &Addr, path: &str) -> bool {
+        self.event == event
+            && match event {
+                "done" => self.selector == "*" || a.is(&self.selector),
+                _ => matches(&self.selector, path, false),
+            }
+    }
+}
+
+/// Read the hooks file, if it exists. The empty lines and the lines
+/// beginning with `#` are ignored.
+pub fn hooks(path: &Path) -> Result<Vec<Hook>, String> {
+    let s = match std::fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(format!("Read {:?} fail: {}", path, err)),
+    };
+    s.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(Hook::parse)
+        .collect()
+}
+#[test]
+fn test_hooks() {
+    use std::convert::TryFrom;
+    let h = Hook::parse("download *.gz  gunzip -k \"$ISAC_LOCAL\"").unwrap();
+    assert_eq!(h.command, "gunzip -k \"$ISAC_LOCAL\"");
+    let a = Addr::try_from("u@h/r").unwrap();
+    assert!(h.selects("download", &a, "logs/a.gz"));
+    assert!(!h.selects("upload", &a, "logs/a.gz"));
+    assert!(!h.selects("download", &a, "logs/a.txt"));
+
+    let h = Hook::parse("done h ./index.sh").unwrap();
+    assert!(h.selects("done", &a, ""));
+    assert!(!h.selects("done", &Addr::try_from("u@other/r").unwrap(), ""));
+    assert!(Hook::parse("start * ls").is_err());
+    assert!(Hook::parse("done *").is_err());
+}
+
+/// Run the local hooks of the event, with the environment variables
+/// `ISAC_HOST`, `ISAC_DIGEST`, `ISAC_ROOT`, `ISAC_ACTION`, `ISAC_PATH`,
+/// `ISAC_LOCAL`, `ISAC_REMOTE` and `ISAC_RESULT`: `ok` or the error.
+pub fn local_hooks(
+    c: &Config,
+    a: &Addr,
+    event: &str,
+    path: &str,
+    local: &Path,
+    remote: &Path,
+    result: &R,
+) -> R {
+    let mut errors = Vec::new();
+    for h in c.hooks.iter().filter(|h| h.selects(event, a, path)) {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(&h.command)
+            .env("ISAC_HOST", format!("{:x}", a))
+            .env("ISAC_DIGEST", &a.digest)
+            .env("ISAC_ROOT", &a.root)
+            .env("ISAC_ACTION", event)
+            .env("ISAC_PATH", path)
+            .env("ISAC_LOCAL", local)
+            .env("ISAC_REMOTE", remote)
+            .env(
+                "ISAC_RESULT",
+                match result {
+                    Ok(()) => "ok",
+                    Err(err) => err.as_str(),
+                },
+            )
+            .status();
+        match status {
+            Ok(s) if s.success() => {}
+            Ok(s) => errors.push(format!(
+                "The {} hook {:?} exit with {}",
+                event, h.command, s
+            )),
+            Err(err) => errors.push(format!(
+                "Run the {} hook {:?} fail: {}",
+                event, h.command, err
+            )),
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors.join(", ")),
+    }
+}
+
+/// Run the `done` hooks of the host with the result of its command, keep
+/// the error of the command.
+pub fn done(c: &Config, a: &Addr, r: R) -> R {
+    let (local, remote) = (Path::new(&a.digest), Path::new(&a.root));
+    file_hooks(c, a, "done", "", local, remote, r)
+}
+
+/// Run the hooks of a copied file after its copy, keep the error of the copy.
+pub fn file_hooks(
+    c: &Config,
+    a: &Addr,
+    event: &str,
+    path: &str,
+    local: &Path,
+    remote: &Path,
+    r: R,
+) -> R {
+    match (local_hooks(c, a, event, path, local, remote, &r), r) {
+        (Err(h), Err(e)) => Err(format!("{}, {}", e, h)),
+        (_, Err(e)) => Err(e),
+        (h, Ok(())) => h,
+    }
+}
@@ -0,0 +1,364 @@
+use super::{Couple, MetaFile};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs::{create_dir_all, rename, File};
+use std::io::Write;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// The state of one file at the last run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Entry {
+    pub dir: bool,
+    /// The remote size and mtime.
+    pub remote: (u64, u64),
+    /// The local size and mtime.
+    pub local: (u64, u64),
+    /// The MD5 of the local content, in hexadecimal.
+    pub hash: Option<String>,
+}
+
+impl Entry {
+    fn changed(&self, f: &MetaFile, (size, mtime): (u64, u64)) -> bool {
+        f.dir != self.dir || (!f.dir && (f.size, f.mtime) != (size, mtime))
+    }
+    pub fn remote_changed(&self, f: &MetaFile) -> bool {
+        self.changed(f, self.remote)
+    }
+    pub fn local_changed(&self, f: &MetaFile) -> bool {
+        self.changed(f, self.local)
+    }
+}
+
+/// All the files of one host at the last run, by relative path.
+#[derive(Debug, Default, PartialEq)]
+pub struct Index {
+    /// The end of the last run, in seconds since UNIX epoch.
+    pub time: u64,
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Index {
+    /// Load the index, a missing file is an empty index.
+    pub fn load(path: &Path) -> Result<Index, String> {
+        match std::fs::read_to_string(path) {
+            Ok(s) => Index::parse(&s).map_err(|err| format!("Parse index {:?}: {}", path, err)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Index::default()),
+            Err(err) => Err(format!("Read index {:?} fail: {}", path, err)),
+        }
+    }
+    /// Save the index in a temporary file then move it.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir).map_err(|err| format!("Create {:?} fail: {}", dir, err))?;
+        }
+        let tmp = path.with_extension("tmp");
+        File::create(&tmp)
+            .and_then(|mut f| write!(f, "{}", self))
+            .map_err(|err| format!("Write index {:?} fail: {}", tmp, err))?;
+        rename(&tmp, path).map_err(|err| format!("Move index {:?} fail: {}", path, err))
+    }
+    fn parse(s: &str) -> Result<Index, String> {
+        let mut index = Index::default();
+        for (i, line) in s.lines().enumerate().filter(|(_, l)| !l.is_empty()) {
+            let n = |f: &str| {
+                f.parse::<u64>()
+                    .map_err(|err| format!("line {}: {:?} {}", i + 1, f, err))
+            };
+            if let Some(t) = line.strip_prefix("time\t") {
+                index.time = n(t)?;
+                continue;
+            }
+
+            let fields: Vec<&str> = line.splitn(7, '\t').collect();
+            if fields.len() != 7 {
+                return Err(format!("line {}: expected 7 fields", i + 1));
+            }
+            index.entries.insert(
+                unescape(fields[6]),
+                Entry {
+                    dir: fields[0] == "d",
+                    remote: (n(fields[1])?, n(fields[2])?),
+                    local: (n(fields[3])?, n(fields[4])?),
+                    hash: match fields[5] {
+                        "-" => None,
+                        h => Some(h.to_string()),
+                    },
+                },
+            );
+        }
+        Ok(index)
+    }
+    pub fn get(&self, path: &str) -> Option<&Entry> {
+        self.entries.get(path)
+    }
+    pub fn insert(&mut self, path: String, entry: Entry) {
+        self.entries.insert(path, entry);
+    }
+    /// The entries inside the directory `dir`, at any depth.
+    pub fn subtree<'a>(&'a self, dir: &'a str) -> impl Iterator<Item = (&'a String, &'a Entry)> {
+        let prefix = match dir.is_empty() {
+            true => String::new(),
+            false => format!("{}/", dir),
+        };
+        self.entries
+            .range(prefix.clone()..)
+            .take_while(move |(p, _)| p.starts_with(&prefix))
+    }
+    /// The names and the entries directly inside the directory `dir`. The
+    /// entries of the sub directories are skipped, not read.
+    pub fn children<'a>(&'a self, dir: &'a str) -> impl Iterator<Item = (&'a str, &'a Entry)> {
+        let prefix = match dir.is_empty() {
+            true => String::new(),
+            false => format!("{}/", dir),
+        };
+        let mut from = Bound::Included(prefix.clone());
+        std::iter::from_fn(move || loop {
+            let (p, e) = self
+                .entries
+                .range((from.clone(), Bound::Unbounded))
+                .next()
+                .filter(|(p, _)| p.starts_with(&prefix))?;
+            let name = &p[prefix.len()..];
+            match name.find('/') {
+                // '0' follows '/', the next key after the subtree.
+                Some(i) => from = Bound::Included(format!("{}{}0", prefix, &name[..i])),
+                None => {
+                    from = Bound::Excluded(p.clone());
+                    return Some((name, e));
+                }
+            }
+        })
+    }
+}
+
+impl fmt::Display for Index {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "time\t{}", self.time)?;
+        for (path, e) in self.entries.iter() {
+            writeln!(
+                f,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                if e.dir { "d" } else { "f" },
+                e.remote.0,
+                e.remote.1,
+                e.local.0,
+                e.local.1,
+                e.hash.as_deref().unwrap_or("-"),
+                escape(path)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\n', "\\n")
+}
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some(c) => out.push(c),
+                None => out.push('\\'),
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+#[test]
+fn index_parse_display() {
+    let mut index = Index {
+        time: 1600000100,
+        ..Index::default()
+    };
+    index.insert(
+        "dir".to_string(),
+        Entry {
+            dir: true,
+            ..Entry::default()
+        },
+    );
+    index.insert(
+        "dir/a\tb\\c\nd".to_string(),
+        Entry {
+            dir: false,
+            remote: (10, 1600000000),
+            local: (10, 1600000042),
+            hash: Some("d41d8cd98f00b204e9800998ecf8427e".to_string()),
+        },
+    );
+    index.insert("dir/sub/e".to_string(), Entry::default());
+    index.insert("dir/sub/f".to_string(), Entry::default());
+    index.insert("dir/z".to_string(), Entry::default());
+    index.insert("dirx".to_string(), Entry::default());
+    assert_eq!(Index::parse(&index.to_string()).unwrap(), index);
+    assert!(Index::parse("f\t1\t2\n").is_err());
+    assert!(Index::parse("f\t1\tx\t3\t4\t-\tpath\n").is_err());
+
+    assert_eq!(
+        index.children("dir").map(|(n, _)| n).collect::<Vec<_>>(),
+        vec!["a\tb\\c\nd", "z"]
+    );
+    assert_eq!(index.subtree("dir").count(), 4);
+    assert_eq!(
+        index.children("").map(|(n, _)| n).collect::<Vec<_>>(),
+        vec!["dir", "dirx"]
+    );
+}
+
+/// The number of entries changed on one side since the last run.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Changes {
+    pub new: u64,
+    pub modified: u64,
+    pub deleted: u64,
+}
+
+impl fmt::Display for Changes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} new, {} modified, {} deleted",
+            self.new, self.modified, self.deleted
+        )
+    }
+}
+
+/// The index of the last run, and the one of this run.
+pub struct Tracker {
+    pub old: Index,
+    new: Mutex<Index>,
+    remote: Mutex<Changes>,
+    local: Mutex<Changes>,
+}
+
+impl Tracker {
+    pub fn new(old: Index) -> Tracker {
+        Tracker {
+            old,
+            new: Mutex::new(Index::default()),
+            remote: Mutex::new(Changes::default()),
+            local: Mutex::new(Changes::default()),
+        }
+    }
+    /// Count the changes of one side in the directory `dir`, from its listing.
+    /// Return the names of the changed entries.
+    pub fn observe(&self, dir: &str, list: &[MetaFile], remote: bool) -> Vec<String> {
+        let mut changed = Vec::new();
+        let mut c = match remote {
+            true => self.remote.lock().unwrap(),
+            false => self.local.lock().unwrap(),
+        };
+        let path = |name: &str| match dir.is_empty() {
+            true => name.to_string(),
+            false => format!("{}/{}", dir, name),
+        };
+
+        for f in list.iter().filter(|f| !f.dir) {
+            let name = f.name.to_string_lossy();
+            match self.old.get(&path(&name)) {
+                None => c.new += 1,
+                Some(e) if remote && e.remote_changed(f) => c.modified += 1,
+                Some(e) if !remote && e.local_changed(f) => c.modified += 1,
+                Some(_) => continue,
+            }
+            changed.push(name.to_string());
+        }
+        let names: HashSet<String> = list
+            .iter()
+            .map(|f| f.name.to_string_lossy().to_string())
+            .collect();
+        for (name, _) in self.old.children(dir).filter(|(_, e)| !e.dir) {
+            if !names.contains(name) {
+                c.deleted += 1;
+                changed.push(name.to_string());
+            }
+        }
+        changed
+    }
+    /// Save the entries of the directory `dir` after this run. If `hash` is
+    /// the local directory, the MD5 of the new and modified files is computed.
+    pub fn record(&self, dir: &str, couples: &[Couple<MetaFile>], hash: Option<&Path>) {
+        let mut new = self.new.lock().unwrap();
+        for (r, l) in couples.iter().filter_map(|c| match c {
+            (Some(r), Some(l)) if r.dir == l.dir => Some((r, l)),
+            _ => None,
+        }) {
+            let name = r.name.to_string_lossy();
+            let path = match dir.is_empty() {
+                true => name.to_string(),
+                false => format!("{}/{}", dir, name),
+            };
+
+            let hash = match (r.dir, self.old.get(&path), hash) {
+                (true, _, _) | (false, _, None) => None,
+                (false, Some(e), _) if !e.local_changed(l) && e.hash.is_some() => e.hash.clone(),
+                (false, _, Some(dir)) => md5_file(&dir.join(&l.name)).ok(),
+            };
+            new.insert(
+                path,
+                Entry {
+                    dir: r.dir,
+                    remote: (r.size, r.mtime),
+                    local: (l.size, l.mtime),
+                    hash,
+                },
+            );
+        }
+    }
+    /// Copy the entries of the subtree `dir` from the last run.
+    pub fn keep(&self, dir: &str) {
+        let mut new = self.new.lock().unwrap();
+        if let Some(e) = self.old.get(dir) {
+            new.insert(dir.to_string(), e.clone());
+        }
+        for (p, e) in self.old.subtree(dir) {
+            new.insert(p.clone(), e.clone());
+        }
+    }
+    /// Copy the entries of the last run whose path matches `f`.
+    pub fn keep_if<F: Fn(&str) -> bool>(&self, f: F) {
+        let mut new = self.new.lock().unwrap();
+        for (p, e) in self.old.subtree("").filter(|(p, _)| f(p)) {
+            new.insert(p.clone(), e.clone());
+        }
+    }
+    /// The changes of the remote and of the local side.
+    pub fn changes(&self) -> (Changes, Changes) {
+        (*self.remote.lock().unwrap(), *self.local.lock().unwrap())
+    }
+    /// Save the index of this run.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut new = std::mem::take(&mut *self.new.lock().unwrap());
+        new.time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        new.save(path)
+    }
+}
+
+/// The MD5 of a local file in hexadecimal.
+pub fn md5_file(path: &Path) -> Result<String, String> {
+    use md5::{Digest, Md5};
+    use std::fmt::Write as fmtWrite;
+
+    let mut hasher = Md5::new();
+    std::io::copy(
+        &mut File::open(path).map_err(|err| format!("Open {:?} fail: {}", path, err))?,
+        &mut hasher,
+    )
+    .map_err(|err| format!("Read {:?} fail: {}", path, err))?;
+
+    let mut s = String::with_capacity(32);
+    for u in hasher.finalize().iter() {
+        write!(&mut s, "{:02x}", u).unwrap();
+    }
+    Ok(s)
+}
@@ -2,8 +2,9 @@
 extern crate lazy_static;
 
 use ssh2::{Session, Sftp};
-use std::fs::{create_dir_all, read_dir, remove_dir_all, File};
-use std::path::PathBuf;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 mod addr;
 pub use addr::{addr_from_reader, Addr};
@@ -15,77 +16,107 @@ use metafile::MetaFile;
 
 mod assets;
 pub use assets::print_err;
+use assets::quote;
 use assets::Assets;
 pub use assets::Key;
 
-pub type R = Result<(), String>;
+mod config;
+pub use config::{parse_size, Config};
 
-fn compare_dir<M>(a: &Assets, remote_dir: &PathBuf, local_dir: &PathBuf, m: M) -> R
-where
-    M: Fn(&Assets, &Couple<MetaFile>, &PathBuf, &PathBuf) -> R,
-{
-    use std::convert::TryFrom;
-
-    a.log("index", remote_dir, None);
-
-    let remote_list: Vec<MetaFile> = a
-        .sftp
-        .readdir(remote_dir)
-        .map_err(|err| format!("Index remote directory {:?} fail: {}", remote_dir, err))?
-        .iter()
-        .filter_map(|f| match MetaFile::try_from(f) {
-            Ok(meta) => Some(meta),
-            Err(err) => {
-                a.err(format!(
-                    "In indexing remote directory {:?}: {}",
-                    remote_dir, err
-                ));
-                None
-            }
-        })
-        .collect();
-
-    let local_list: Vec<MetaFile> = read_dir(local_dir)
-        .map_err(|err| format!("Index local directory {:?} fail: {}", local_dir, err))?
-        .filter_map(|r| match r {
-            Ok(f) => Some(f),
-            Err(err) => {
-                a.err(format!(
-                    "In indexing local directory {:?}: {}",
-                    local_dir, err
-                ));
-                None
-            }
-        })
-        .filter_map(|f| match MetaFile::try_from(f) {
-            Ok(meta) => Some(meta),
-            Err(err) => {
-                a.err(format!(
-                    "In indexing local directory {:?}: {}",
-                    local_dir, err
-                ));
-                None
-            }
-        })
-        .collect();
+mod filter;
+use filter::{matches, Filter, IGNORE};
 
-    linkvec(remote_list, local_list)
-        .iter()
-        .map(|couple| m(a, couple, &remote_dir, &local_dir))
-        .filter_map(|r| r.err())
-        .for_each(|err| a.err(err));
+mod index;
+use index::{md5_file, Entry, Index, Tracker};
 
-    Ok(())
+mod crypt;
+use crypt::OVERHEAD;
+pub use crypt::{Crypt, SECRET};
+
+mod owner;
+pub use owner::Owner;
+use owner::{remote_chown, Owners};
+
+mod hook;
+pub use hook::{done, hooks, Hook, HOOKS};
+use hook::{file_hooks, remote_hook};
+
+mod lock;
+use lock::LOCK;
+pub use lock::{lock_dir, lock_host, Lock};
+
+mod space;
+use space::check_space;
+pub use space::Space;
+
+mod limit;
+pub use limit::Bucket;
+use limit::Limit;
+
+mod transfer;
+use transfer::{Direction, Transfers};
+
+mod walk;
+use walk::{
+    exclude, list_local, list_remote, local_unchanged, mkdir_all, read_remote, remove_dir,
+    remove_local_dir, scopes, tree_unchanged, walk, Dir,
+};
+
+mod store;
+use store::Store;
+
+mod snapshot;
+pub use snapshot::{snapshots, Retention};
+
+mod restore;
+pub use restore::{restore, Restore};
+
+mod mirror;
+pub use mirror::mirror;
+
+mod archive;
+pub use archive::{archive, Archive, Compression};
+
+mod single;
+pub use single::{get, put, Single};
+
+mod watch;
+pub use watch::watch;
+
+mod status;
+pub use status::status;
+
+mod verify;
+use verify::remote_hash;
+pub use verify::verify;
+
+mod daemon;
+pub use daemon::{daemon, daemon_status, parse_duration};
+
+mod moves;
+use moves::Moves;
+
+mod sync;
+pub use sync::{sync, Conflict};
+
+/// The directory of the isac state files, in the isac directory.
+const STATE: &str = ".isac";
+
+/// The path of the state file `name` of the host.
+fn state(a: &Addr, name: &str) -> PathBuf {
+    PathBuf::from(STATE).join(&a.digest).join(name)
 }
 
+pub type R = Result<(), String>;
+
 /* PRINT DETAIL */
 
-pub fn connect(a: Addr, ansi: bool, key: Key) -> R {
-    Assets::new(a, ansi, key).map(|_| ())
+pub fn connect(a: Addr, c: Arc<Config>) -> R {
+    Assets::new(a, c).map(|_| ())
 }
 
-pub fn list(a: Addr, ansi: bool, _: Key) -> R {
-    if ansi {
+pub fn list(a: Addr, c: Arc<Config>) -> R {
+    if c.ansi {
         println!(
             "\x1b[1m{:>12} \x1b[32m{} \x1b[0m<-> \x1b[1;34m{:x}\x1b[36m{}\x1b[0m",
             "list", a.digest, a, a.root
@@ -99,44 +130,84 @@ pub fn list(a: Addr, ansi: bool, _: Key) -> R {
 
 /* UPLOAD */
 
-pub fn upload(a: Addr, ansi: bool, key: Key) -> R {
-    let assets = Assets::new(a, ansi, key)?;
+pub fn upload(a: Addr, c: Arc<Config>) -> R {
+    let mut assets = Assets::new(a, c)?;
+    remote_hook(&assets, "pre")?;
+    let root = Dir::root(
+        &assets,
+        remote_root(&assets)?,
+        PathBuf::from(&assets.a.digest),
+    );
+    let (top, local) = (root.remote.clone(), root.local.clone());
+    let dirs = scopes(&assets, root)?;
+    // The remote sizes and names of the encrypted files aren't the ones of
+    // the index.
+    let moves = match assets.c.detect_moves && assets.c.crypt.is_none() {
+        true => Some(Moves::new(&assets.index.old, &dirs, top.clone(), local)),
+        false => None,
+    };
+    for dir in dirs.iter() {
+        mkdir_all(&assets, &dir.remote)?;
+    }
+    check_space(&mut assets, &dirs, Direction::Upload, &top)?;
+    for dir in dirs {
+        walk(&assets, dir, |a, couple, dir, queue| {
+            upload_couple(a, moves.as_ref(), couple, dir, queue)
+        })?;
+    }
+    if let Some(moves) = moves {
+        moves.finish(&assets);
+    }
+    assets.save_index()?;
+    assets.result()?;
+    remote_hook(&assets, "post")
+}
 
-    let root = PathBuf::from(&assets.a.root);
-    if let Err(e) = assets.sftp.opendir(&root) {
+/// Get the remote root, create it if it doesn't exist.
+fn remote_root(a: &Assets) -> Result<PathBuf, String> {
+    let root = PathBuf::from(&a.a.root);
+    if let Err(e) = a.sftp.opendir(&root) {
         if e.code() == -31 {
-            assets.log("mkdir", &root, None);
-            assets
-                .sftp
+            a.act("mkdir", &root, None);
+            a.sftp
                 .mkdir(&root, 0o0777)
                 .map_err(|err| format!("make remote root directory {:?} fail {}", root, err))?;
         }
     }
-
-    upload_dir(&assets, &root, &PathBuf::from(&assets.a.digest))
+    Ok(root)
 }
 
 fn upload_couple(
     a: &Assets,
+    moves: Option<&Moves>,
     couple: &Couple<MetaFile>,
-    remote_dir: &PathBuf,
-    local_dir: &PathBuf,
+    dir: &Dir,
+    queue: &mut Vec<Dir>,
 ) -> R {
     match couple {
         (Some(remote), Some(local)) => {
-            let r = remote_dir.join(&remote.name);
+            let r = dir.remote_path(&remote.name)?;
             if local.dir != remote.dir {
                 match remote.dir {
-                    true => remove_dir(a, &r)?,
+                    true => remove_dir(a, &r, &dir.filter.enter(&remote.name))?,
                     false => a
                         .sftp
                         .unlink(&r)
                         .map_err(|err| format!("Remove {:?} fail {}", &r, err))?,
                 }
-                upload_couple(a, &(None, Some(local.clone())), remote_dir, local_dir)
+                upload_couple(a, moves, &(None, Some(local.clone())), dir, queue)
             } else {
+                let sub = dir.enter(&local.name)?;
                 match remote.dir {
-                    true => upload_dir(a, &r, &local_dir.join(&local.name)),
+                    true if a.c.trust_index && local_unchanged(a, &sub.local, &sub.filter) => {
+                        a.log("skip", &r, None);
+                        a.index.keep(sub.filter.dir());
+                        Ok(())
+                    }
+                    true => {
+                        queue.push(sub);
+                        Ok(())
+                    }
                     false => {
                         a.log("keep", &r, Some(remote.size));
                         Ok(())
@@ -145,11 +216,19 @@ fn upload_couple(
             }
         }
         (Some(remote), None) => {
-            let r = remote_dir.join(&remote.name);
-            match remote.dir {
-                true => remove_dir(a, &r),
-                false => {
-                    a.log("rm", &r, None);
+            let r = dir.remote_path(&remote.name)?;
+            let filter = match remote.dir {
+                true => Some(dir.filter.enter(&remote.name)),
+                false => None,
+            };
+            match (moves, filter) {
+                (Some(moves), filter) => {
+                    moves.remove_later(r, dir.filter.path(&remote.name), filter);
+                    Ok(())
+                }
+                (None, Some(filter)) => remove_dir(a, &r, &filter),
+                (None, None) => {
+                    a.act("rm", &r, None);
                     a.sftp
                         .unlink(&r)
                         .map_err(|err| format!("Remove file {:?} fail {}", r, err))
@@ -157,19 +236,48 @@ fn upload_couple(
             }
         }
         (None, Some(local)) => {
-            let r = remote_dir.join(&local.name);
-            let l = local_dir.join(&local.name);
+            let sub = dir.enter(&local.name)?;
             match local.dir {
                 true => {
-                    a.log("mkdir", &r, None);
+                    a.act("mkdir", &sub.remote, None);
                     a.sftp
-                        .mkdir(&r, 0o0777)
-                        .map_err(|err| format!("Make directory {:?} fail {}", &r, err))?;
-                    upload_dir(a, &r, &l)
+                        .mkdir(&sub.remote, 0o0777)
+                        .map_err(|err| format!("Make directory {:?} fail {}", &sub.remote, err))?;
+                    if let Some(owners) = &a.owners {
+                        let ids = owners.remote(&sub.local, sub.filter.dir())?;
+                        remote_chown(&a.sftp, &sub.remote, ids)?;
+                    }
+                    queue.push(sub);
+                    Ok(())
+                }
+                false
+                    if moves.is_some_and(|m| m.rename(a, local, sub.filter.dir(), &sub.local)) =>
+                {
+                    Ok(())
                 }
                 false => {
-                    a.log("upload", &r, Some(local.size));
-                    upload_file(a, &r, &l)
+                    // Give the remote file the owner of the local one, with `--owner`.
+                    let ids = match &a.owners {
+                        Some(owners) => Some(owners.remote(&sub.local, sub.filter.dir())?),
+                        None => None,
+                    };
+                    let (c, addr, name) = (a.c.clone(), a.a.clone(), sub.filter.dir().to_string());
+                    let (remote, path) = (sub.remote.clone(), sub.local.clone());
+                    a.act("upload", &sub.remote, Some(local.size));
+                    a.transfers.copy(
+                        Direction::Upload,
+                        sub.remote,
+                        sub.local,
+                        local.size,
+                        move |sftp, r| {
+                            let r = match ids {
+                                Some(ids) => r.and_then(|_| remote_chown(sftp, &remote, ids)),
+                                None => r,
+                            };
+                            file_hooks(&c, &addr, "upload", &name, &path, &remote, r)
+                        },
+                    );
+                    Ok(())
                 }
             }
         }
@@ -177,139 +285,178 @@ fn upload_couple(
     }
 }
 
-fn remove_dir(a: &Assets, remote_dir: &PathBuf) -> R {
-    a.sftp
-        .readdir(&remote_dir)
-        .map_err(|err| format!("Read file (to remove it) {:?} fail {}", &remote_dir, err))?
-        .iter()
-        .map(|(p, stat)| match stat.is_dir() {
-            true => remove_dir(a, &p),
-            false => {
-                a.log("rm", &p, None);
-                a.sftp
-                    .unlink(&p)
-                    .map_err(|err| format!("Remove file {:?} fail {}", &p, err))
-            }
-        })
-        .filter_map(|r| r.err())
-        .for_each(|err| a.err(err));
-
-    a.log("rmdir", &remote_dir, None);
-    a.sftp
-        .rmdir(&remote_dir)
-        .map_err(|err| format!("Remove empty directory {:?} fail {}", &remote_dir, err))
-}
-
-fn upload_dir(a: &Assets, remote_dir: &PathBuf, local_dir: &PathBuf) -> R {
-    compare_dir(a, remote_dir, local_dir, upload_couple)
-}
-
-fn upload_file(a: &Assets, remote_path: &PathBuf, local_path: &PathBuf) -> R {
-    std::io::copy(
-        &mut File::open(local_path)
-            .map_err(|err| format!("Open local file {:?} fail {}", local_path, err))?,
-        &mut a
-            .sftp
-            .create(remote_path)
-            .map_err(|err| format!("Create remote file {:?} fail {:?}", remote_path, err))?,
-    )
-    .map_err(|err| format!("Copy of {:?} fail {}", remote_path, err))
-    .map(|_| ())
-}
-
 /* DOWNLOAD */
 
-pub fn download(a: Addr, ansi: bool, key: Key) -> R {
-    let assets = Assets::new(a, ansi, key)?;
-
-    create_dir_all(&assets.a.digest)
-        .map_err(|err| format!("Create {:?} directory fail: {}", &assets.a.digest, err))?;
+pub fn download(a: Addr, c: Arc<Config>) -> R {
+    let mut assets = Assets::new(a, c)?;
+    remote_hook(&assets, "pre")?;
 
-    download_dir(
-        &assets,
-        &PathBuf::from(&assets.a.root),
-        &PathBuf::from(&assets.a.digest),
-    )
-}
-
-fn download_dir(a: &Assets, remote_dir: &PathBuf, local_dir: &PathBuf) -> R {
-    compare_dir(a, remote_dir, local_dir, download_couple)
+    let local = match assets.c.snapshot {
+        true => snapshot::start(&assets)?,
+        false => {
+            create_dir_all(&assets.a.digest)
+                .map_err(|err| format!("Create {:?} directory fail: {}", &assets.a.digest, err))?;
+            PathBuf::from(&assets.a.digest)
+        }
+    };
+
+    let root = Dir::root(&assets, PathBuf::from(&assets.a.root), local.clone());
+    let store = match assets.c.dedup {
+        true => Some(Arc::new(Store::open()?)),
+        false => None,
+    };
+    let dirs = scopes(&assets, root)?;
+    for dir in dirs.iter() {
+        create_dir_all(&dir.local)
+            .map_err(|err| format!("Create {:?} directory fail: {}", dir.local, err))?;
+    }
+    check_space(&mut assets, &dirs, Direction::Download, &local)?;
+    for dir in dirs {
+        walk(&assets, dir, |a, couple, dir, queue| {
+            download_couple(a, store.as_ref(), couple, dir, queue)
+        })?;
+    }
+    assets.save_index()?;
+    if assets.c.snapshot {
+        snapshot::finish(&assets, &local)?;
+    }
+    assets.result()?;
+    remote_hook(&assets, "post")
 }
 
 fn download_couple(
     a: &Assets,
+    store: Option<&Arc<Store>>,
     couple: &Couple<MetaFile>,
-    remote_dir: &PathBuf,
-    local_dir: &PathBuf,
+    dir: &Dir,
+    queue: &mut Vec<Dir>,
 ) -> R {
     match couple {
         (Some(remote), Some(local)) => {
-            let remote_path = remote_dir.join(&remote.name);
-            let local_path = local_dir.join(&local.name);
+            let sub = dir.enter(&remote.name)?;
             match (remote.dir, local.dir) {
-                (true, true) => download_dir(a, &remote_path, &local_path),
+                (true, true) => {
+                    queue.push(sub);
+                    Ok(())
+                }
                 (true, false) => {
-                    a.log("rm", &local_path, None);
-                    std::fs::remove_file(&local_path)
-                        .map_err(|err| format!("Remove {:?} fail: {}", local_path, err))?;
-                    download_couple(a, &(Some(remote.clone()), None), remote_dir, local_dir)
+                    a.act("rm", &sub.local, None);
+                    std::fs::remove_file(&sub.local)
+                        .map_err(|err| format!("Remove {:?} fail: {}", sub.local, err))?;
+                    download_couple(a, store, &(Some(remote.clone()), None), dir, queue)
                 }
                 (false, true) => {
-                    a.log("rmdir", &local_dir, None);
-                    remove_dir_all(&local_path)
-                        .map_err(|err| format!("Remove dir {:?} fail {}", local_path, err))?;
-                    a.log("download", &remote_path, Some(remote.size));
-                    download_file(a, &remote_path, &local_path)
+                    remove_local_dir(a, &sub.local, &sub.filter)?;
+                    download_file(a, store, sub, remote)
                 }
                 (false, false) => {
                     if remote.mtime < local.mtime {
                         return Ok(());
                     }
-                    a.log("download", &remote_path, Some(remote.size));
-                    download_file(a, &remote_path, &local_path)
+                    // The linked files keep the mtime of the first download.
+                    if store.is_some() {
+                        match a.index.old.get(sub.filter.dir()) {
+                            Some(e) if !e.remote_changed(remote) && !e.local_changed(local) => {
+                                return Ok(())
+                            }
+                            _ => {}
+                        }
+                    }
+                    download_file(a, store, sub, remote)
                 }
             }
         }
         (Some(f), None) => {
-            let remote_path = remote_dir.join(&f.name);
-            let local_path = local_dir.join(&f.name);
+            let sub = dir.enter(&f.name)?;
             match f.dir {
                 true => {
-                    a.log("mkdir", &remote_path, None);
-                    std::fs::create_dir(&local_path)
-                        .map_err(|err| format!("Make dir {:?} fail {}", local_path, err))?;
-                    download_dir(a, &remote_path, &local_path)
-                }
-                false => {
-                    a.log("download", &remote_path, Some(f.size));
-                    download_file(a, &remote_path, &local_path)
+                    a.act("mkdir", &sub.remote, None);
+                    std::fs::create_dir(&sub.local)
+                        .map_err(|err| format!("Make dir {:?} fail {}", sub.local, err))?;
+                    if let (Some(owners), Some((uid, gid))) = (&a.owners, f.owner) {
+                        owners.chown(&sub.local, sub.filter.dir(), uid, gid)?;
+                    }
+                    queue.push(sub);
+                    Ok(())
                 }
+                false => download_file(a, store, sub, f),
             }
         }
         (None, Some(f)) => {
-            let p = local_dir.join(&f.name);
-            a.log("rm", &p, None);
+            let p = dir.local.join(&f.name);
             match f.dir {
-                true => std::fs::remove_dir_all(&p),
-                false => std::fs::remove_file(&p),
+                true => remove_local_dir(a, &p, &dir.filter.enter(&f.name)),
+                false => {
+                    a.act("rm", &p, None);
+                    std::fs::remove_file(&p).map_err(|err| format!("rm of {:?} fail {}", p, err))
+                }
             }
-            .map_err(|err| format!("rm of {:?} fail {}", p, err))
         }
         (None, None) => Ok(()),
     }
 }
 
-fn download_file(a: &Assets, remote_path: &PathBuf, local_path: &PathBuf) -> R {
-    let mut remote_file = a
-        .sftp
-        .open(&remote_path)
-        .map_err(|err| format!("Open remote file {:?} fail {}", remote_path, err))?;
-
-    let mut local_file = File::create(&local_path)
-        .map_err(|err| format!("Create local file {:?} fail {}", local_path, err))?;
-
-    std::io::copy(&mut remote_file, &mut local_file)
-        .map_err(|err| format!("Copy {:?} fail {}", remote_path, err))?;
+/// Download a file, or link it from the store if its content is already there.
+fn download_file(a: &Assets, store: Option<&Arc<Store>>, sub: Dir, f: &MetaFile) -> R {
+    let size = f.size;
+    let name = sub.filter.dir().to_string();
+    // Give the local file the owner of the remote one, with `--owner`.
+    let owner = match (&a.owners, f.owner) {
+        (Some(owners), Some(ids)) => Some((owners.clone(), ids)),
+        _ => None,
+    };
+    let (c, addr, remote) = (a.c.clone(), a.a.clone(), sub.remote.clone());
+    let then = move |local: &PathBuf, r: R| {
+        let r = match owner {
+            Some((owners, (uid, gid))) => r.and_then(|_| owners.chown(local, &name, uid, gid)),
+            None => r,
+        };
+        file_hooks(&c, &addr, "download", &name, local, &remote, r)
+    };
+    if let Some(store) = store.filter(|s| s.has_size(size)) {
+        if let Some(hash) = remote_md5(a, &sub.remote) {
+            match store.get(&hash, size, &sub.local) {
+                Ok(true) => {
+                    a.act("link", &sub.local, Some(size));
+                    return then(&sub.local, Ok(()));
+                }
+                Ok(false) => {}
+                Err(err) => a.err(err),
+            }
+        }
+    }
 
+    a.act("download", &sub.remote, Some(size));
+    let local = sub.local.clone();
+    let store = store.cloned();
+    a.transfers.copy(
+        Direction::Download,
+        sub.remote,
+        sub.local,
+        size,
+        move |_, r| {
+            let r = match &store {
+                Some(store) => r.and_then(|_| store.add(&local)),
+                None => r,
+            };
+            then(&local, r)
+        },
+    );
     Ok(())
 }
+
+/// The MD5 of a remote file, with `md5sum`. None for an encrypted file.
+fn remote_md5(a: &Assets, remote: &Path) -> Option<String> {
+    if a.c.crypt.is_some() {
+        return None;
+    }
+    let out = a
+        .exec(&format!("md5sum -- {}", quote(&remote.to_string_lossy())))
+        .map_err(|err| a.err(err))
+        .ok()?;
+    let hash = out.split_whitespace().next()?;
+    match hash.len() == 32 && hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        true => Some(hash.to_ascii_lowercase()),
+        false => None,
+    }
+}
@@ -0,0 +1,98 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A token bucket of bytes, shared by the threads it limits.
+pub struct Bucket {
+    /// The bytes per second.
+    rate: u64,
+    /// The available bytes, negative if the threads are in debt, and the
+    /// time of the last refill.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    pub fn new(rate: u64) -> Bucket {
+        Bucket {
+            rate: rate.max(1),
+            state: Mutex::new((0.0, Instant::now())),
+        }
+    }
+    /// Take `n` bytes, sleep if the bucket is in debt. The bucket holds at
+    /// most one second of bytes.
+    pub fn take(&self, n: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let rate = self.rate as f64;
+            state.0 = (state.0 + now.duration_since(state.1).as_secs_f64() * rate).min(rate);
+            state.1 = now;
+            state.0 -= n as f64;
+            match state.0 < 0.0 {
+                true => -state.0 / rate,
+                false => 0.0,
+            }
+        };
+        if wait > 0.0 {
+            std::thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
+
+impl fmt::Debug for Bucket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Bucket({} o/s)", self.rate)
+    }
+}
+#[test]
+fn test_bucket() {
+    let b = Bucket::new(1000);
+    let before = Instant::now();
+    b.take(100);
+    b.take(100);
+    assert!(before.elapsed() >= Duration::from_millis(150));
+    assert!(before.elapsed() < Duration::from_secs(1));
+}
+
+/// The limits of the transfers of one host, and the transferred bytes.
+#[derive(Debug, Default)]
+pub struct Limit {
+    buckets: Vec<Arc<Bucket>>,
+    bytes: AtomicU64,
+}
+
+impl Limit {
+    pub fn new(buckets: Vec<Arc<Bucket>>) -> Limit {
+        Limit {
+            buckets,
+            bytes: AtomicU64::new(0),
+        }
+    }
+    /// The bytes transferred until now.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::SeqCst)
+    }
+    /// Wrap the source of a copy.
+    pub fn reader<'a, T: Read>(&'a self, inner: T) -> Limited<'a, T> {
+        Limited { limit: self, inner }
+    }
+}
+
+/// A reader that counts the bytes and waits for all the buckets.
+pub struct Limited<'a, T> {
+    limit: &'a Limit,
+    inner: T,
+}
+
+impl<'a, T: Read> Read for Limited<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.limit.bytes.fetch_add(n as u64, Ordering::SeqCst);
+        for b in self.limit.buckets.iter() {
+            b.take(n as u64);
+        }
+        Ok(n)
+    }
+}
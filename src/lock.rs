@@ -0,0 +1,317 @@
+use super::{print_err, state, Addr, Assets, Config, STATE};
+use ssh2::{OpenFlags, OpenType, RenameFlags, Sftp};
+use std::fmt;
+use std::fs::{create_dir_all, hard_link, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+
+/// The name of the lock file in the remote root, with `--remote-lock`.
+pub const LOCK: &str = ".isac-lock";
+
+/// The number of times a lock file that can't be read or is empty is read
+/// again, 100 ms apart, before failing.
+const TRIES: u32 = 50;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn hostname() -> String {
+    hostname::get()
+        .ok()
+        .and_then(|h| h.into_string().ok())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+/// The process that holds a lock: the content of the lock file.
+#[derive(Debug, PartialEq)]
+struct Holder {
+    pid: u32,
+    host: String,
+    /// The time of the lock, in seconds since the epoch.
+    time: u64,
+}
+
+impl Holder {
+    fn me() -> Holder {
+        Holder {
+            pid: std::process::id(),
+            host: hostname(),
+            time: now(),
+        }
+    }
+    fn parse(s: &str) -> Option<Holder> {
+        let mut f = s.split_whitespace();
+        let pid = f.next()?.parse().ok()?;
+        let host = f.next()?.to_string();
+        let time = f.next()?.parse().ok()?;
+        Some(Holder { pid, host, time })
+    }
+    /// The process is gone: it was on this host and it's not running, or
+    /// the lock is older than `stale` seconds.
+    fn stale(&self, host: &str, now: u64, stale: u64, alive: impl Fn(u32) -> bool) -> bool {
+        match self.host == host {
+            true => !alive(self.pid),
+            false => now.saturating_sub(self.time) > stale,
+        }
+    }
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "pid {} on {} for {} s",
+            self.pid,
+            self.host,
+            now().saturating_sub(self.time)
+        )
+    }
+}
+#[test]
+fn test_holder() {
+    let h = Holder::parse("42 backup 1600000000\n").unwrap();
+    assert_eq!(h.pid, 42);
+    assert_eq!(h.host, "backup");
+    assert_eq!(h.time, 1600000000);
+    assert_eq!(Holder::parse("42 backup"), None);
+    assert_eq!(Holder::parse("x backup 1600000000"), None);
+
+    let (alive, dead) = (|_| true, |_| false);
+    assert!(!h.stale("backup", 1600090000, 86400, alive));
+    assert!(h.stale("backup", 1600000001, 86400, dead));
+    assert!(!h.stale("other", 1600080000, 86400, dead));
+    assert!(h.stale("other", 1600090000, 86400, alive));
+}
+
+/// Check if a local process is running.
+fn alive(pid: u32) -> bool {
+    match unsafe { libc::kill(pid as libc::pid_t, 0) } {
+        0 => true,
+        _ => std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM),
+    }
+}
+
+/// Where a lock file is.
+enum Side {
+    Local,
+    Remote(Sftp),
+}
+
+impl Side {
+    /// Create the lock file, false if it already exists.
+    fn create(&self, path: &Path, content: &str) -> Result<bool, String> {
+        let err = |err: &dyn fmt::Display| format!("Create lock {:?} fail: {}", path, err);
+        match self {
+            Side::Local => match OpenOptions::new().write(true).create_new(true).open(path) {
+                Ok(mut f) => f.write_all(content.as_bytes()).map_err(|e| err(&e))?,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => return Ok(false),
+                Err(e) => return Err(err(&e)),
+            },
+            Side::Remote(sftp) => {
+                let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE;
+                match sftp.open_mode(path, flags, 0o644, OpenType::File) {
+                    Ok(mut f) => f.write_all(content.as_bytes()).map_err(|e| err(&e))?,
+                    // The servers don't agree on the error of an existing file.
+                    Err(_) if sftp.lstat(path).is_ok() => return Ok(false),
+                    Err(e) => return Err(err(&e)),
+                }
+            }
+        }
+        Ok(true)
+    }
+    fn read(&self, path: &Path) -> Option<String> {
+        let mut s = String::new();
+        match self {
+            Side::Local => std::fs::File::open(path)
+                .ok()?
+                .read_to_string(&mut s)
+                .ok()?,
+            Side::Remote(sftp) => sftp.open(path).ok()?.read_to_string(&mut s).ok()?,
+        };
+        Some(s)
+    }
+    /// Move the file, without replacing `to` unless `replace`.
+    fn rename(&self, from: &Path, to: &Path, replace: bool) -> Result<(), String> {
+        match (self, replace) {
+            (Side::Local, true) => std::fs::rename(from, to).map_err(|err| err.to_string()),
+            (Side::Local, false) => hard_link(from, to)
+                .and_then(|_| std::fs::remove_file(from))
+                .map_err(|err| err.to_string()),
+            (Side::Remote(sftp), replace) => {
+                let mut flags = RenameFlags::ATOMIC | RenameFlags::NATIVE;
+                if replace {
+                    flags |= RenameFlags::OVERWRITE;
+                }
+                sftp.rename(from, to, Some(flags))
+                    .map_err(|err| err.to_string())
+            }
+        }
+        .map_err(|err| format!("Move lock {:?} to {:?} fail: {}", from, to, err))
+    }
+    /// Remove the stale lock whose content is `stale`. It's moved to a name
+    /// of this run first, so two runs can't both take it over. If another
+    /// run replaced it meanwhile, the new lock is moved back.
+    fn take_over(&self, path: &Path, stale: &str, me: &Holder) -> Result<(), String> {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}.{}", me.host, me.pid));
+        let mine = PathBuf::from(name);
+        if let Err(err) = self.rename(path, &mine, true) {
+            // Another run took it over, else it can't be moved.
+            return match self.read(path) {
+                Some(s) if s == stale => Err(err),
+                _ => Ok(()),
+            };
+        }
+        match self.read(&mine) {
+            Some(s) if s == stale => self.remove(&mine),
+            _ => self.rename(&mine, path, false),
+        }
+    }
+    fn remove(&self, path: &Path) -> Result<(), String> {
+        match self {
+            Side::Local => std::fs::remove_file(path).map_err(|err| err.to_string()),
+            Side::Remote(sftp) => sftp.unlink(path).map_err(|err| err.to_string()),
+        }
+        .map_err(|err| format!("Remove lock {:?} fail: {}", path, err))
+    }
+}
+
+#[test]
+fn test_take_over() {
+    let dir = std::env::temp_dir().join(format!("isac-lock-{}", std::process::id()));
+    create_dir_all(&dir).unwrap();
+    let (path, me) = (dir.join("lock"), Holder::me());
+    std::fs::write(&path, "1 old 0\n").unwrap();
+    Side::Local.take_over(&path, "1 old 0\n", &me).unwrap();
+    assert!(!path.exists());
+
+    // A lock replaced since it was read is kept.
+    std::fs::write(&path, "2 new 0\n").unwrap();
+    Side::Local.take_over(&path, "1 old 0\n", &me).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "2 new 0\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Print a message about a lock, of the host `a` or of the isac directory.
+fn print(ansi: bool, op: &str, a: Option<&Addr>, ms: &str) {
+    match (a, ansi) {
+        (Some(a), true) => eprintln!("\x1b[1m{:>12} \x1b[1;34m{:x}\x1b[0m {}", op, a, ms),
+        (Some(a), false) => eprintln!("{:>12}: <{:x}> {}", op, a, ms),
+        (None, true) => eprintln!("\x1b[1m{:>12}\x1b[0m {}", op, ms),
+        (None, false) => eprintln!("{:>12}: {}", op, ms),
+    }
+}
+
+/// A lock file, removed when it's dropped.
+pub struct Lock {
+    side: Side,
+    path: PathBuf,
+    /// The host of the lock, none for the isac directory.
+    a: Option<Addr>,
+    ansi: bool,
+}
+
+impl Lock {
+    /// Create the lock file. A stale lock is replaced, a held one is an
+    /// error, or waited for with `--wait`.
+    fn acquire(side: Side, path: PathBuf, c: &Config, a: Option<&Addr>) -> Result<Lock, String> {
+        let me = Holder::me();
+        let content = format!("{} {} {}\n", me.pid, me.host, me.time);
+        let mut waiting = false;
+        let mut tries = 0;
+        loop {
+            if side.create(&path, &content)? {
+                return Ok(Lock {
+                    side,
+                    path,
+                    a: a.cloned(),
+                    ansi: c.ansi,
+                });
+            }
+            // The lock may be removed or not written yet when it's read.
+            let (s, holder) = match side.read(&path) {
+                Some(s) => match Holder::parse(&s) {
+                    Some(h) => (s, h),
+                    None if !s.is_empty() => {
+                        return Err(format!("Bad lock {:?}: {:?}, remove it", path, s))
+                    }
+                    None if tries < TRIES => {
+                        tries += 1;
+                        sleep(Duration::from_millis(100));
+                        continue;
+                    }
+                    None => return Err(format!("The lock {:?} stays empty, remove it", path)),
+                },
+                None if tries < TRIES => {
+                    tries += 1;
+                    sleep(Duration::from_millis(100));
+                    continue;
+                }
+                None => return Err(format!("Read lock {:?} fail", path)),
+            };
+            tries = 0;
+            if holder.stale(&me.host, now(), c.stale_lock, alive) {
+                let ms = format!("{:?} of {}", path, holder);
+                print(c.ansi, "stale lock", a, &ms);
+                side.take_over(&path, &s, &me)?;
+                continue;
+            }
+            if !c.wait {
+                return Err(format!("{:?} is locked by {}", path, holder));
+            }
+            if !waiting {
+                let ms = format!("{:?} is locked by {}", path, holder);
+                print(c.ansi, "wait", a, &ms);
+                waiting = true;
+            }
+            sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if let Err(err) = self.side.remove(&self.path) {
+            match &self.a {
+                Some(a) => print_err(err, a, self.ansi),
+                None => print(self.ansi, "ERROR", None, &err),
+            }
+        }
+    }
+}
+
+/// Lock the isac directory for the whole run.
+pub fn lock_dir(c: &Config) -> Result<Lock, String> {
+    create_dir_all(STATE).map_err(|err| format!("Create {:?} fail: {}", STATE, err))?;
+    Lock::acquire(Side::Local, Path::new(STATE).join("lock"), c, None)
+}
+
+/// Lock the host: `.isac/<digest>/lock`, and `<root>/.isac-lock` on the
+/// host with `--remote-lock`.
+pub fn lock_host(a: &Addr, c: &Config) -> Result<Vec<Lock>, String> {
+    let path = state(a, "lock");
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir).map_err(|err| format!("Create {:?} fail: {}", dir, err))?;
+    }
+    let mut locks = vec![Lock::acquire(Side::Local, path, c, Some(a))?];
+    if c.remote_lock {
+        let sftp = Assets::connect(a, &c.key)?;
+        let root = PathBuf::from(&a.root);
+        if sftp.opendir(&root).is_err() {
+            sftp.mkdir(&root, 0o0777)
+                .map_err(|err| format!("make remote root directory {:?} fail {}", root, err))?;
+        }
+        locks.push(Lock::acquire(
+            Side::Remote(sftp),
+            root.join(LOCK),
+            c,
+            Some(a),
+        )?);
+    }
+    Ok(locks)
+}
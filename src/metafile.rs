@@ -1,6 +1,7 @@
 use std::cmp::{Ordering, PartialEq, PartialOrd};
 use std::convert::TryFrom;
-use std::fs::DirEntry;
+use std::fs::{DirEntry, Metadata};
+use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
 use std::time::SystemTime;
 
@@ -10,6 +11,34 @@ pub struct MetaFile {
     pub dir: bool,
     pub size: u64,
     pub mtime: u64,
+    /// The type and the permissions, as `st_mode`, 0 if unknown.
+    pub mode: u32,
+    /// The uid and the gid, if known.
+    pub owner: Option<(u32, u32)>,
+}
+
+impl MetaFile {
+    pub fn symlink(&self) -> bool {
+        self.mode & 0o170000 == 0o120000
+    }
+    /// The entry `name` of a local directory, from its metadata.
+    pub fn local(name: PathBuf, info: &Metadata) -> Result<MetaFile, String> {
+        Ok(MetaFile {
+            mtime: match info
+                .modified()
+                .map_err(|err| format!("Get modified information about {:?} {}", name, err))?
+                .duration_since(SystemTime::UNIX_EPOCH)
+            {
+                Ok(d) => d.as_secs(),
+                Err(_) => 0,
+            },
+            name,
+            dir: info.is_dir(),
+            size: info.len(),
+            mode: info.mode(),
+            owner: Some((info.uid(), info.gid())),
+        })
+    }
 }
 
 impl PartialEq for MetaFile {
@@ -31,10 +60,12 @@ impl TryFrom<&(PathBuf, ssh2::FileStat)> for MetaFile {
             Some(n) => PathBuf::from(n),
         };
         let mut m = MetaFile {
-            name: name,
+            name,
             dir: f.is_dir(),
             size: 0,
             mtime: f.mtime.unwrap_or(0),
+            mode: f.perm.unwrap_or(0),
+            owner: f.uid.zip(f.gid),
         };
         if !m.dir {
             m.size = f.size.ok_or(format!("File {:?} has no size", n))?
@@ -49,19 +80,6 @@ impl TryFrom<DirEntry> for MetaFile {
         let info = f
             .metadata()
             .map_err(|err| format!("Get Metadato of {:?} fail: {}", name, err))?;
-
-        Ok(MetaFile {
-            mtime: match info
-                .modified()
-                .map_err(|err| format!("Get modified information about {:?} {}", name, err))?
-                .duration_since(SystemTime::UNIX_EPOCH)
-            {
-                Ok(d) => d.as_secs(),
-                Err(_) => 0,
-            },
-            name: name,
-            dir: info.is_dir(),
-            size: info.len(),
-        })
+        MetaFile::local(name, &info)
     }
 }
@@ -0,0 +1,160 @@
+use super::{
+    done, exclude, linkvec, list_remote, print_err, read_remote, remote_root, remove_dir, Addr,
+    Assets, Config, Couple, Filter, MetaFile, IGNORE, R,
+};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Copy the tree of the host `source` to each target, without a local copy.
+/// The targets are mirrored at the same time. Fail if a target fails.
+pub fn mirror(source: Addr, targets: Vec<Addr>, c: Arc<Config>) -> R {
+    let count = targets.len();
+    let threads: Vec<_> = targets
+        .into_iter()
+        .map(|target| {
+            let (source, c) = (source.clone(), c.clone());
+            std::thread::spawn(move || {
+                let ansi = c.ansi;
+                let r = mirror_to(source, target.clone(), c.clone());
+                match done(&c, &target, r) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        print_err(err, &target, ansi);
+                        false
+                    }
+                }
+            })
+        })
+        .collect();
+    let mut failed = 0;
+    for t in threads {
+        if !t
+            .join()
+            .map_err(|_| "A mirror thread panicked".to_string())?
+        {
+            failed += 1;
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(format!("{} of the {} targets failed", failed, count)),
+    }
+}
+
+/// Make the tree of `target` the same as the one of `source`.
+fn mirror_to(source: Addr, target: Addr, c: Arc<Config>) -> R {
+    let src = Assets::new(source, c.clone())?;
+    let dst = Assets::new(target, c)?;
+
+    let mut queue = vec![(
+        PathBuf::from(&src.a.root),
+        remote_root(&dst)?,
+        Filter::new(&dst.c, &dst.a),
+    )];
+    while let Some((s, t, filter)) = queue.pop() {
+        dst.log("index", &t, None);
+        let (s_list, t_list) = match (list_remote(&src, &s), list_remote(&dst, &t)) {
+            (Ok(s), Ok(t)) => (s, t),
+            (Err(err), _) | (_, Err(err)) => {
+                dst.err(err);
+                continue;
+            }
+        };
+        let filter = ignore_files(&dst, [(&src, &s, &s_list), (&dst, &t, &t_list)], &filter);
+        let couples = linkvec(exclude(s_list, &filter), exclude(t_list, &filter));
+        for couple in couples.iter() {
+            if let Err(err) = mirror_couple(&src, &dst, couple, (&s, &t, &filter), &mut queue) {
+                dst.err(err);
+            }
+        }
+    }
+    dst.transfers.join();
+    dst.result()
+}
+
+/// Add to the filter the patterns of the ignore files of the source and of
+/// the target directory. The errors are reported on the target.
+fn ignore_files(
+    dst: &Assets,
+    dirs: [(&Assets, &PathBuf, &[MetaFile]); 2],
+    filter: &Arc<Filter>,
+) -> Arc<Filter> {
+    let mut patterns = String::new();
+    for (a, dir, list) in dirs.iter() {
+        if !list.iter().any(|f| !f.dir && f.name == Path::new(IGNORE)) {
+            continue;
+        }
+        match read_remote(a, &dir.join(IGNORE)) {
+            Ok(s) => {
+                patterns.push_str(&s);
+                patterns.push('\n');
+            }
+            Err(err) => dst.err(err),
+        }
+    }
+    filter.with(&patterns)
+}
+
+fn mirror_couple(
+    src: &Assets,
+    dst: &Assets,
+    couple: &Couple<MetaFile>,
+    (s, t, filter): (&PathBuf, &PathBuf, &Arc<Filter>),
+    queue: &mut Vec<(PathBuf, PathBuf, Arc<Filter>)>,
+) -> R {
+    match couple {
+        (Some(from), Some(to)) if from.dir && to.dir => {
+            queue.push((s.join(&from.name), t.join(&to.name), filter.enter(&to.name)));
+            Ok(())
+        }
+        (Some(from), Some(to)) if from.dir != to.dir => {
+            remove(dst, &t.join(&to.name), to.dir, filter.enter(&to.name))?;
+            mirror_couple(src, dst, &(Some(from.clone()), None), (s, t, filter), queue)
+        }
+        (Some(from), Some(to)) => {
+            let p = t.join(&to.name);
+            match from.size == to.size && from.mtime <= to.mtime {
+                true => {
+                    dst.log("keep", &p, Some(to.size));
+                    Ok(())
+                }
+                false => {
+                    dst.act("relay", &p, Some(from.size));
+                    dst.transfers.relay(src.sftp.clone(), s.join(&from.name), p);
+                    Ok(())
+                }
+            }
+        }
+        (Some(from), None) => {
+            let p = t.join(&from.name);
+            match from.dir {
+                true => {
+                    dst.act("mkdir", &p, None);
+                    dst.sftp
+                        .mkdir(&p, 0o0777)
+                        .map_err(|err| format!("Make directory {:?} fail {}", p, err))?;
+                    queue.push((s.join(&from.name), p, filter.enter(&from.name)));
+                }
+                false => {
+                    dst.act("relay", &p, Some(from.size));
+                    dst.transfers.relay(src.sftp.clone(), s.join(&from.name), p);
+                }
+            }
+            Ok(())
+        }
+        (None, Some(to)) => remove(dst, &t.join(&to.name), to.dir, filter.enter(&to.name)),
+        (None, None) => Ok(()),
+    }
+}
+
+fn remove(dst: &Assets, p: &PathBuf, dir: bool, filter: Arc<Filter>) -> R {
+    match dir {
+        true => remove_dir(dst, p, &filter),
+        false => {
+            dst.act("rm", p, None);
+            dst.sftp
+                .unlink(p)
+                .map_err(|err| format!("Remove file {:?} fail {}", p, err))
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use super::{md5_file, remove_dir, Assets, Dir, Filter, Index, MetaFile};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Detect the files moved locally since the last run, to rename them on the
+/// remote instead of deleting and uploading them again.
+pub struct Moves {
+    remote_root: PathBuf,
+    local_root: PathBuf,
+    /// The files of the last run, by local size and mtime.
+    by_stat: HashMap<(u64, u64), Vec<String>>,
+    /// The sources already renamed.
+    moved: Mutex<HashSet<String>>,
+    /// The remote files and directories to remove at the end, they can be
+    /// the source of a move found later.
+    removed: Mutex<Vec<Removed>>,
+}
+
+/// A remote path to remove, its path in the index and its filter if it's a
+/// directory.
+type Removed = (PathBuf, String, Option<Arc<Filter>>);
+
+impl Moves {
+    /// The sources are the files of the last run compared by `dirs`.
+    pub fn new(old: &Index, dirs: &[Dir], remote_root: PathBuf, local_root: PathBuf) -> Moves {
+        let mut by_stat: HashMap<(u64, u64), Vec<String>> = HashMap::new();
+        for (path, e) in old
+            .subtree("")
+            .filter(|(p, e)| !e.dir && dirs.iter().any(|d| d.contains(p)))
+        {
+            by_stat.entry(e.local).or_default().push(path.clone());
+        }
+        Moves {
+            remote_root,
+            local_root,
+            by_stat,
+            moved: Mutex::new(HashSet::new()),
+            removed: Mutex::new(Vec::new()),
+        }
+    }
+    /// Remove later the remote file, or the directory with its filter.
+    pub fn remove_later(&self, remote: PathBuf, path: String, filter: Option<Arc<Filter>>) {
+        self.removed.lock().unwrap().push((remote, path, filter));
+    }
+    /// Rename the remote source of the new local file `local` at `path`, if
+    /// it's a file moved since the last run. Return true if renamed.
+    pub fn rename(&self, a: &Assets, local: &MetaFile, path: &str, local_path: &Path) -> bool {
+        let candidates = match self.by_stat.get(&(local.size, local.mtime)) {
+            Some(c) => c,
+            None => return false,
+        };
+        let mut hash = None;
+        let mut moved = self.moved.lock().unwrap();
+        let source = candidates.iter().find(|&p| {
+            let e = match a.index.old.get(p) {
+                Some(e) => e,
+                None => return false,
+            };
+            if moved.contains(p) || self.local_root.join(p).symlink_metadata().is_ok() {
+                return false;
+            }
+            // With the hashes of the last run, check the content.
+            if let Some(h) = &e.hash {
+                let hash = hash.get_or_insert_with(|| md5_file(local_path).ok());
+                if hash.as_ref() != Some(h) {
+                    return false;
+                }
+            }
+            match a.sftp.stat(&self.remote_root.join(p)) {
+                Ok(s) => (s.size, s.mtime) == (Some(e.remote.0), Some(e.remote.1)),
+                Err(_) => false,
+            }
+        });
+        let source = match source {
+            Some(s) => s.clone(),
+            None => return false,
+        };
+
+        let (from, to) = (self.remote_root.join(&source), self.remote_root.join(path));
+        a.act("rename", &to, Some(local.size));
+        match a.sftp.rename(&from, &to, None) {
+            Ok(()) => {
+                moved.insert(source);
+                true
+            }
+            Err(err) => {
+                a.err(format!("Rename {:?} to {:?} fail {}", from, to, err));
+                false
+            }
+        }
+    }
+    /// Remove the remote files and directories that weren't moved.
+    pub fn finish(&self, a: &Assets) {
+        let moved = self.moved.lock().unwrap();
+        for (remote, path, filter) in std::mem::take(&mut *self.removed.lock().unwrap()) {
+            match filter {
+                Some(filter) => {
+                    if let Err(err) = remove_dir(a, &remote, &filter) {
+                        a.err(err);
+                    }
+                }
+                None if moved.contains(&path) => {}
+                None => {
+                    a.act("rm", &remote, None);
+                    if let Err(err) = a.sftp.unlink(&remote) {
+                        a.err(format!("Remove file {:?} fail {}", remote, err));
+                    }
+                }
+            }
+        }
+    }
+}
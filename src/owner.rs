@@ -0,0 +1,236 @@
+use super::{state, Assets, R};
+use ssh2::{FileStat, Sftp};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::fs::{rename, File};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+/// How the owners of the files are kept between the hosts and the local copy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Owner {
+    /// The same uid and gid on both sides.
+    Numeric,
+    /// The uid and gid of the user and the group of the same name.
+    Name,
+}
+
+impl std::str::FromStr for Owner {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "numeric" => Ok(Owner::Numeric),
+            "name" => Ok(Owner::Name),
+            _ => Err(format!("Unknown owner {:?}, expected numeric or name", s)),
+        }
+    }
+}
+
+/// The command listing the users and the groups, on both sides.
+const LIST: &str = "getent passwd || cat /etc/passwd; echo; getent group || cat /etc/group";
+
+/// Parse the `passwd` then the `group` entries separated by an empty line,
+/// into the ids by name.
+fn parse_ids(s: &str) -> (HashMap<String, u32>, HashMap<String, u32>) {
+    let mut maps = (HashMap::new(), HashMap::new());
+    let mut groups = false;
+    for line in s.lines() {
+        if line.trim().is_empty() {
+            groups = true;
+            continue;
+        }
+        let fields: Vec<&str> = line.split(':').collect();
+        if let (Some(name), Some(Ok(id))) = (fields.first(), fields.get(2).map(|i| i.parse())) {
+            match groups {
+                true => maps.1.insert(name.to_string(), id),
+                false => maps.0.insert(name.to_string(), id),
+            };
+        }
+    }
+    maps
+}
+
+/// The local ids of the remote ids, by name.
+fn mapping(remote: &HashMap<String, u32>, local: &HashMap<String, u32>) -> HashMap<u32, u32> {
+    remote
+        .iter()
+        .filter_map(|(name, r)| local.get(name).map(|l| (*r, *l)))
+        .collect()
+}
+/// The remote ids of the local ids. When several remote ids have the same
+/// local one, the smallest is taken.
+fn reverse(map: &HashMap<u32, u32>) -> HashMap<u32, u32> {
+    let mut pairs: Vec<(&u32, &u32)> = map.iter().collect();
+    pairs.sort();
+    let mut back = HashMap::new();
+    for (r, l) in pairs.into_iter() {
+        back.entry(*l).or_insert(*r);
+    }
+    back
+}
+#[test]
+fn test_ids() {
+    let remote = parse_ids(
+        "root:x:0:0:root:/root:/bin/sh\nalice:x:1001:1001::/home/alice:/bin/sh\n\nroot:x:0:\nstaff:x:50:alice\n",
+    );
+    assert_eq!(remote.0.get("alice"), Some(&1001));
+    assert_eq!(remote.1.get("staff"), Some(&50));
+    let local = parse_ids(
+        "alice:x:1000:1000::/home/alice:/bin/sh\nbob:x:1001:1001::/:/bin/sh\n\nstaff:x:20:\n",
+    );
+    let users = mapping(&remote.0, &local.0);
+    assert_eq!(users.get(&1001), Some(&1000));
+    assert_eq!(users.get(&0), None);
+    assert_eq!(mapping(&remote.1, &local.1).get(&50), Some(&20));
+
+    let map: HashMap<u32, u32> = [(1002, 1000), (1001, 1000), (7, 8)]
+        .iter()
+        .cloned()
+        .collect();
+    let back = reverse(&map);
+    assert_eq!(back.get(&1000), Some(&1001));
+    assert_eq!(back.get(&8), Some(&7));
+}
+
+/// The owners of the files of a host, with the ones that the local process
+/// can't give to the local copy, saved in `.isac/<digest>/owners`.
+pub struct Owners {
+    /// The local uid and gid of the remote ones, empty for numeric.
+    users: HashMap<u32, u32>,
+    groups: HashMap<u32, u32>,
+    /// The remote uid and gid of the local ones.
+    back_users: HashMap<u32, u32>,
+    back_groups: HashMap<u32, u32>,
+    /// The remote uid and gid by path of the index, when chown fails.
+    sidecar: Mutex<BTreeMap<String, (u32, u32)>>,
+    path: PathBuf,
+}
+
+impl Owners {
+    pub fn new(a: &Assets, owner: Owner) -> Result<Owners, String> {
+        let (users, groups) = match owner {
+            Owner::Numeric => (HashMap::new(), HashMap::new()),
+            Owner::Name => {
+                let remote = parse_ids(&a.exec(LIST)?);
+                let out = Command::new("sh")
+                    .arg("-c")
+                    .arg(LIST)
+                    .output()
+                    .map_err(|err| format!("List the local users fail: {}", err))?;
+                let local = parse_ids(&String::from_utf8_lossy(&out.stdout));
+                (mapping(&remote.0, &local.0), mapping(&remote.1, &local.1))
+            }
+        };
+        let path = state(&a.a, "owners");
+        let mut sidecar = BTreeMap::new();
+        match std::fs::read_to_string(&path) {
+            Ok(s) => {
+                for line in s.lines() {
+                    let f: Vec<&str> = line.splitn(3, '\t').collect();
+                    match (
+                        f.len(),
+                        f.first().map(|u| u.parse()),
+                        f.get(1).map(|g| g.parse()),
+                    ) {
+                        (3, Some(Ok(uid)), Some(Ok(gid))) => {
+                            sidecar.insert(f[2].to_string(), (uid, gid))
+                        }
+                        _ => return Err(format!("Bad line in {:?}: {:?}", path, line)),
+                    };
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(format!("Read {:?} fail: {}", path, err)),
+        }
+        Ok(Owners {
+            back_users: reverse(&users),
+            back_groups: reverse(&groups),
+            users,
+            groups,
+            sidecar: Mutex::new(sidecar),
+            path,
+        })
+    }
+
+    /// Give the local file the owner of the remote one. If the process
+    /// can't, the remote owner is kept in the sidecar.
+    pub fn chown(&self, local: &Path, name: &str, uid: u32, gid: u32) -> R {
+        let (u, g) = (
+            *self.users.get(&uid).unwrap_or(&uid),
+            *self.groups.get(&gid).unwrap_or(&gid),
+        );
+        let c = CString::new(local.as_os_str().as_bytes())
+            .map_err(|err| format!("Chown {:?} fail: {}", local, err))?;
+        let mut sidecar = self.sidecar.lock().unwrap();
+        if unsafe { libc::lchown(c.as_ptr(), u, g) } == 0 {
+            sidecar.remove(name);
+            return Ok(());
+        }
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EPERM) => {
+                sidecar.insert(name.to_string(), (uid, gid));
+                Ok(())
+            }
+            _ => Err(format!("Chown {:?} fail: {}", local, err)),
+        }
+    }
+
+    /// The remote owner of the local file: the one of the sidecar, or the
+    /// one of the file.
+    pub fn remote(&self, local: &Path, name: &str) -> Result<(u32, u32), String> {
+        if let Some(ids) = self.sidecar.lock().unwrap().get(name) {
+            return Ok(*ids);
+        }
+        let m = std::fs::symlink_metadata(local)
+            .map_err(|err| format!("Stat local file {:?} fail: {}", local, err))?;
+        let back = |map: &HashMap<u32, u32>, id: u32| *map.get(&id).unwrap_or(&id);
+        Ok((
+            back(&self.back_users, m.uid()),
+            back(&self.back_groups, m.gid()),
+        ))
+    }
+
+    /// Save the sidecar, removed when it's empty.
+    pub fn save(&self) -> R {
+        let sidecar = self.sidecar.lock().unwrap();
+        if sidecar.is_empty() {
+            return match std::fs::remove_file(&self.path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    Err(format!("Remove {:?} fail: {}", self.path, err))
+                }
+                _ => Ok(()),
+            };
+        }
+        let tmp = self.path.with_extension("tmp");
+        File::create(&tmp)
+            .and_then(|mut f| {
+                for (name, (uid, gid)) in sidecar.iter() {
+                    writeln!(f, "{}\t{}\t{}", uid, gid, name)?;
+                }
+                Ok(())
+            })
+            .map_err(|err| format!("Write {:?} fail: {}", tmp, err))?;
+        rename(&tmp, &self.path).map_err(|err| format!("Move {:?} fail: {}", self.path, err))
+    }
+}
+
+/// Give the remote file this owner.
+pub fn remote_chown(sftp: &Sftp, remote: &Path, (uid, gid): (u32, u32)) -> R {
+    sftp.setstat(
+        remote,
+        FileStat {
+            size: None,
+            uid: Some(uid),
+            gid: Some(gid),
+            perm: None,
+            atime: None,
+            mtime: None,
+        },
+    )
+    .map_err(|err| format!("Chown remote file {:?} fail {}", remote, err))
+}
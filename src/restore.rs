@@ -0,0 +1,192 @@
+use super::{
+    mkdir_all, remove_dir, snapshot, walk, Addr, Assets, Config, Couple, Dir, Direction, MetaFile,
+    R,
+};
+use std::io::BufRead;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// What to restore, from the `restore` command.
+#[derive(Debug, Default, Clone)]
+pub struct Restore {
+    /// The name of the snapshot, or `latest`. By default the last snapshot
+    /// if there is one, else the mirror.
+    pub snapshot: Option<String>,
+    /// Restore only this sub directory, relative to the root.
+    pub path: Option<PathBuf>,
+    /// The remote root, instead of the one of the list.
+    pub to: Option<String>,
+    /// Keep the remote files that exist instead of overwriting them.
+    pub keep: bool,
+    /// Remove the remote files that aren't in the local copy.
+    pub delete: bool,
+    /// Only print what would be done.
+    pub dry_run: bool,
+    /// Don't ask a confirmation before overwriting or removing files.
+    pub yes: bool,
+}
+
+/// One pass of the restore.
+struct Plan<'a> {
+    r: &'a Restore,
+    dry_run: bool,
+    /// The number of files overwritten or removed.
+    destructive: AtomicU64,
+}
+
+/// Upload a mirror or a snapshot back to the host.
+pub fn restore(a: Addr, c: Arc<Config>, r: &Restore) -> R {
+    let assets = Assets::new(a, c)?;
+    let remote = PathBuf::from(r.to.as_ref().unwrap_or(&assets.a.root));
+    let mut root = Dir::root(&assets, remote, source(&assets, r)?);
+    if let Some(path) = &r.path {
+        for c in path.components() {
+            match c {
+                Component::Normal(name) => root = root.enter(Path::new(name))?,
+                Component::CurDir => {}
+                _ => return Err(format!("The path {:?} must be relative to the root", path)),
+            }
+        }
+    }
+    if !root.local.is_dir() {
+        return Err(format!("{:?} is not a local directory", root.local));
+    }
+
+    let exists = assets.sftp.stat(&root.remote).is_ok();
+    if r.dry_run {
+        assets.ms("dry-run", "nothing is modified");
+        if !exists {
+            assets.log("mkdir", &root.remote, None);
+            assets.log("upload", &root.local, None);
+            return Ok(());
+        }
+    }
+    if exists && !r.yes && !r.dry_run {
+        // Print the plan and ask before the destructive steps.
+        let plan = Plan {
+            r,
+            dry_run: true,
+            destructive: AtomicU64::new(0),
+        };
+        walk(&assets, root.clone(), |a, couple, dir, queue| {
+            restore_couple(a, &plan, couple, dir, queue)
+        })?;
+        let n = plan.destructive.load(Ordering::SeqCst);
+        if n > 0 && !confirm(&format!("Overwrite or remove {} files on {}?", n, assets.a))? {
+            return Err("Restore cancelled".to_string());
+        }
+    }
+    if !exists && !r.dry_run {
+        mkdir_all(&assets, &root.remote)?;
+    }
+
+    let plan = Plan {
+        r,
+        dry_run: r.dry_run,
+        destructive: AtomicU64::new(0),
+    };
+    walk(&assets, root, |a, couple, dir, queue| {
+        restore_couple(a, &plan, couple, dir, queue)
+    })?;
+    assets.transfers.join();
+    assets.result()
+}
+
+/// The local directory to restore.
+fn source(a: &Assets, r: &Restore) -> Result<PathBuf, String> {
+    let dir = snapshot::dir(&a.a);
+    let last = || snapshot::list(&dir).pop().map(|(_, p)| p);
+    match r.snapshot.as_deref() {
+        None => Ok(last().unwrap_or_else(|| PathBuf::from(&a.a.digest))),
+        Some("latest") => last().ok_or_else(|| format!("No snapshot in {:?}", dir)),
+        Some(name) => match snapshot::parse(name).is_some() && dir.join(name).is_dir() {
+            true => Ok(dir.join(name)),
+            false => Err(format!("No snapshot {:?} in {:?}", name, dir)),
+        },
+    }
+}
+
+/// Ask a question on the terminal, return true if the answer is yes.
+fn confirm(question: &str) -> Result<bool, String> {
+    println!("{} [y/N]", question);
+    let mut answer = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .map_err(|err| format!("Read the answer fail: {}", err))?;
+    Ok(["y", "yes"].contains(&answer.trim().to_lowercase().as_str()))
+}
+
+fn restore_couple(
+    a: &Assets,
+    plan: &Plan,
+    couple: &Couple<MetaFile>,
+    dir: &Dir,
+    queue: &mut Vec<Dir>,
+) -> R {
+    let (r, dry_run) = (plan.r, plan.dry_run);
+    match couple {
+        (Some(remote), Some(local)) if remote.dir && local.dir => {
+            queue.push(dir.enter(&local.name)?);
+            Ok(())
+        }
+        (Some(remote), Some(_)) if r.keep => {
+            a.log("keep", &dir.remote_path(&remote.name)?, Some(remote.size));
+            Ok(())
+        }
+        (Some(remote), Some(local)) if !remote.dir && !local.dir => {
+            plan.destructive.fetch_add(1, Ordering::SeqCst);
+            upload(a, dry_run, dir.enter(&local.name)?, local.size)
+        }
+        (Some(remote), Some(local)) => {
+            remove(a, plan, dir, remote)?;
+            restore_couple(a, plan, &(None, Some(local.clone())), dir, queue)
+        }
+        (Some(remote), None) if r.delete => remove(a, plan, dir, remote),
+        (Some(remote), None) => {
+            a.log("keep", &dir.remote_path(&remote.name)?, None);
+            Ok(())
+        }
+        (None, Some(local)) if local.dir => {
+            let sub = dir.enter(&local.name)?;
+            a.act("mkdir", &sub.remote, None);
+            if !dry_run {
+                a.sftp
+                    .mkdir(&sub.remote, 0o0777)
+                    .map_err(|err| format!("Make directory {:?} fail {}", sub.remote, err))?;
+                queue.push(sub);
+            }
+            Ok(())
+        }
+        (None, Some(local)) => upload(a, dry_run, dir.enter(&local.name)?, local.size),
+        (None, None) => Ok(()),
+    }
+}
+
+fn upload(a: &Assets, dry_run: bool, sub: Dir, size: u64) -> R {
+    a.act("upload", &sub.remote, Some(size));
+    if !dry_run {
+        a.transfers
+            .copy(Direction::Upload, sub.remote, sub.local, size, |_, r| r);
+    }
+    Ok(())
+}
+
+fn remove(a: &Assets, plan: &Plan, dir: &Dir, remote: &MetaFile) -> R {
+    plan.destructive.fetch_add(1, Ordering::SeqCst);
+    let r = dir.remote_path(&remote.name)?;
+    match (plan.dry_run, remote.dir) {
+        (true, _) => {
+            a.log("rm", &r, None);
+            Ok(())
+        }
+        (false, true) => remove_dir(a, &r, &dir.filter.enter(&remote.name)),
+        (false, false) => {
+            a.act("rm", &r, None);
+            a.sftp
+                .unlink(&r)
+                .map_err(|err| format!("Remove file {:?} fail {}", r, err))
+        }
+    }
+}
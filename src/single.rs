@@ -0,0 +1,101 @@
+use super::{Addr, Assets, Config, Direction, R};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The paths of the `get` and `put` commands. They can hold `{host}`,
+/// `{user}`, `{digest}` and `{name}`, the name of the copied file.
+#[derive(Debug, Default, Clone)]
+pub struct Single {
+    /// The remote path, relative to the root if it isn't absolute.
+    pub remote: String,
+    pub local: String,
+}
+
+/// Replace the variables of the template.
+fn template(t: &str, a: &Addr, name: &str) -> String {
+    t.replace("{host}", &a.host)
+        .replace("{user}", &a.user)
+        .replace("{digest}", &a.digest)
+        .replace("{name}", name)
+}
+#[test]
+fn test_template() {
+    let a = Addr {
+        user: "alice".to_string(),
+        host: "example.com".to_string(),
+        port: None,
+        root: "/data".to_string(),
+        digest: "abc".to_string(),
+        options: Vec::new(),
+    };
+    assert_eq!(template("{host}/{name}", &a, "f.txt"), "example.com/f.txt");
+    assert_eq!(template("{user}-{digest}.log", &a, "f"), "alice-abc.log");
+    assert_eq!(template("plain", &a, "f"), "plain");
+}
+
+/// The destination: in the directory if it ends with `/` or is an existing
+/// directory, with the name of the source.
+fn destination(path: String, name: &str, is_dir: bool) -> PathBuf {
+    match path.ends_with('/') || is_dir {
+        true => Path::new(&path).join(name),
+        false => PathBuf::from(path),
+    }
+}
+
+/// The name of the file of the path.
+fn name(path: &Path) -> Result<String, String> {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .ok_or_else(|| format!("No file name in {:?}", path))
+}
+
+/// Download one remote file.
+pub fn get(a: Addr, c: Arc<Config>, s: &Single) -> R {
+    let assets = Assets::new(a, c)?;
+    let remote = Path::new(&assets.a.root).join(template(&s.remote, &assets.a, ""));
+    let name = name(&remote)?;
+    let stat = assets
+        .sftp
+        .stat(&remote)
+        .map_err(|err| format!("Stat remote file {:?} fail {}", remote, err))?;
+    if stat.is_dir() {
+        return Err(format!("{:?} is a directory", remote));
+    }
+
+    let local = template(&s.local, &assets.a, &name);
+    let is_dir = Path::new(&local).is_dir();
+    let local = destination(local, &name, is_dir);
+    if let Some(dir) = local.parent().filter(|d| !d.as_os_str().is_empty()) {
+        create_dir_all(dir).map_err(|err| format!("Create {:?} directory fail: {}", dir, err))?;
+    }
+
+    let size = stat.size.unwrap_or(0);
+    assets.act("download", &remote, Some(size));
+    assets
+        .transfers
+        .copy(Direction::Download, remote, local, size, |_, r| r);
+    Ok(())
+}
+
+/// Upload one local file.
+pub fn put(a: Addr, c: Arc<Config>, s: &Single) -> R {
+    let assets = Assets::new(a, c)?;
+    let local = PathBuf::from(template(&s.local, &assets.a, ""));
+    let name = name(&local)?;
+    let meta = std::fs::metadata(&local)
+        .map_err(|err| format!("Stat local file {:?} fail: {}", local, err))?;
+    if meta.is_dir() {
+        return Err(format!("{:?} is a directory", local));
+    }
+
+    let remote = Path::new(&assets.a.root).join(template(&s.remote, &assets.a, &name));
+    let is_dir = assets.sftp.stat(&remote).is_ok_and(|s| s.is_dir());
+    let remote = destination(remote.to_string_lossy().into_owned(), &name, is_dir);
+
+    assets.act("upload", &remote, Some(meta.len()));
+    assets
+        .transfers
+        .copy(Direction::Upload, remote, local, meta.len(), |_, r| r);
+    Ok(())
+}
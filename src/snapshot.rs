@@ -0,0 +1,304 @@
+use super::{state, Addr, Assets, Config, R};
+use separator::Separatable;
+use std::collections::HashSet;
+use std::fs::{create_dir, create_dir_all, hard_link, read_dir, read_link, remove_dir_all};
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// The suffix of a snapshot not finished.
+const PARTIAL: &str = ".partial";
+
+/// How many snapshots to keep, the last one of each hour, day or week. A
+/// none rule keeps all the snapshots.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Retention {
+    pub hourly: Option<usize>,
+    pub daily: Option<usize>,
+    pub weekly: Option<usize>,
+}
+
+/// The days since UNIX epoch of a date, from Howard Hinnant's algorithms.
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (m + if m > 2 { -3 } else { 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+pub fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400;
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+/// The name of the snapshot of this time: `YYYY-MM-DD-HHMMSS` in UTC.
+pub fn name(secs: u64) -> String {
+    let (y, m, d) = civil_from_days((secs / 86400) as i64);
+    let s = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}-{:02}{:02}{:02}",
+        y,
+        m,
+        d,
+        s / 3600,
+        s / 60 % 60,
+        s % 60
+    )
+}
+
+/// The time of a snapshot from its name.
+pub fn parse(name: &str) -> Option<u64> {
+    let b = name.as_bytes();
+    if b.len() != 17 || b[4] != b'-' || b[7] != b'-' || b[10] != b'-' {
+        return None;
+    }
+    let n = |r: std::ops::Range<usize>| name.get(r)?.parse::<u64>().ok();
+    let (y, m, d) = (n(0..4)?, n(5..7)?, n(8..10)?);
+    let (h, min, s) = (n(11..13)?, n(13..15)?, n(15..17)?);
+    if m == 0 || m > 12 || d == 0 || d > 31 || h > 23 || min > 59 || s > 59 {
+        return None;
+    }
+    let days = days_from_civil(y as i64, m as i64, d as i64) as u64;
+    Some(days * 86400 + h * 3600 + min * 60 + s)
+}
+
+/// The times to keep among `times`, the most recent is always kept.
+fn retain(times: &[u64], r: &Retention) -> HashSet<u64> {
+    let mut sorted = times.to_vec();
+    sorted.sort_unstable_by(|a, b| b.cmp(a));
+    if r.hourly.is_none() && r.daily.is_none() && r.weekly.is_none() {
+        return sorted.into_iter().collect();
+    }
+
+    let mut keep: HashSet<u64> = sorted.first().cloned().into_iter().collect();
+    // Thursday 1970-01-01 is the day 0, the weeks start on Monday.
+    let rules = [
+        (r.hourly, 3600, 0),
+        (r.daily, 86400, 0),
+        (r.weekly, 7 * 86400, 3 * 86400),
+    ];
+    for (count, period, shift) in rules.iter() {
+        let count = match count {
+            Some(c) => *c,
+            None => continue,
+        };
+        let mut last = None;
+        let mut kept = 0;
+        for &t in sorted.iter() {
+            if kept >= count {
+                break;
+            }
+            let p = (t + shift) / period;
+            if last != Some(p) {
+                last = Some(p);
+                kept += 1;
+                keep.insert(t);
+            }
+        }
+    }
+    keep
+}
+#[test]
+fn test_snapshot_time() {
+    assert_eq!(name(0), "1970-01-01-000000");
+    assert_eq!(name(1600000000), "2020-09-13-122640");
+    assert_eq!(parse("2020-09-13-122640"), Some(1600000000));
+    assert_eq!(parse("1970-01-01-000000"), Some(0));
+    assert_eq!(parse("2020-09-13-122640.partial"), None);
+    assert_eq!(parse("2020-13-13-122640"), None);
+
+    let h = 3600;
+    let times = [0, h / 2, h, 2 * h, 25 * h, 8 * 24 * h];
+    let all = retain(&times, &Retention::default());
+    assert_eq!(all.len(), times.len());
+
+    let mut r = Retention {
+        hourly: Some(2),
+        ..Retention::default()
+    };
+    let mut kept: Vec<u64> = retain(&times, &r).into_iter().collect();
+    kept.sort();
+    assert_eq!(kept, vec![25 * h, 8 * 24 * h]);
+
+    r.daily = Some(3);
+    let mut kept: Vec<u64> = retain(&times, &r).into_iter().collect();
+    kept.sort();
+    assert_eq!(kept, vec![2 * h, 25 * h, 8 * 24 * h]);
+
+    let r = Retention {
+        weekly: Some(5),
+        ..Retention::default()
+    };
+    let mut kept: Vec<u64> = retain(&times, &r).into_iter().collect();
+    kept.sort();
+    assert_eq!(kept, vec![25 * h, 8 * 24 * h]);
+}
+
+/// The finished snapshots of a host directory, from the oldest.
+pub fn list(dir: &Path) -> Vec<(u64, PathBuf)> {
+    let mut list: Vec<(u64, PathBuf)> = match read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+            .filter_map(|e| Some((parse(e.file_name().to_str()?)?, e.path())))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    list.sort();
+    list
+}
+
+/// The directory of the snapshots of a host, out of its mirror so they
+/// aren't compared with the remote tree.
+pub fn dir(a: &Addr) -> PathBuf {
+    state(a, "snapshots")
+}
+
+/// Start a new snapshot: a copy of the last one with hardlinks, where the
+/// files are downloaded. Return its directory.
+pub fn start(a: &Assets) -> Result<PathBuf, String> {
+    let dir = dir(&a.a);
+    create_dir_all(&dir).map_err(|err| format!("Create {:?} directory fail: {}", dir, err))?;
+
+    // Remove the snapshots not finished.
+    for e in read_dir(&dir)
+        .map_err(|err| format!("Read {:?} fail: {}", dir, err))?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().ends_with(PARTIAL))
+    {
+        a.act("rm", &e.path(), None);
+        remove_dir_all(e.path()).map_err(|err| format!("Remove {:?} fail: {}", e.path(), err))?;
+    }
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let new = dir.join(format!("{}{}", name(now), PARTIAL));
+    match list(&dir).pop() {
+        Some((_, last)) => {
+            a.act("link", &last, None);
+            link_tree(&last, &new)?;
+        }
+        None => create_dir(&new).map_err(|err| format!("Create {:?} fail: {}", new, err))?,
+    }
+    Ok(new)
+}
+
+/// Copy a directory tree with hardlinks.
+fn link_tree(src: &Path, dst: &Path) -> R {
+    let mut queue = vec![(src.to_path_buf(), dst.to_path_buf())];
+    while let Some((s, d)) = queue.pop() {
+        create_dir(&d).map_err(|err| format!("Create {:?} fail: {}", d, err))?;
+        for e in read_dir(&s).map_err(|err| format!("Read {:?} fail: {}", s, err))? {
+            let e = e.map_err(|err| format!("Read {:?} fail: {}", s, err))?;
+            let (sp, dp) = (e.path(), d.join(e.file_name()));
+            let t = e
+                .file_type()
+                .map_err(|err| format!("Stat {:?} fail: {}", sp, err))?;
+            if t.is_dir() {
+                queue.push((sp, dp));
+            } else if t.is_symlink() {
+                read_link(&sp)
+                    .and_then(|target| symlink(target, &dp))
+                    .map_err(|err| format!("Copy the link {:?} fail: {}", sp, err))?;
+            } else {
+                hard_link(&sp, &dp)
+                    .map_err(|err| format!("Link {:?} to {:?} fail: {}", sp, dp, err))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Finish the snapshot, then remove the old ones out of the retention. A
+/// run with errors leaves the snapshot not finished.
+pub fn finish(a: &Assets, new: &Path) -> R {
+    let errors = a.errors.load(Ordering::SeqCst);
+    if errors > 0 {
+        return Err(format!(
+            "{} errors, the snapshot {:?} isn't finished",
+            errors, new
+        ));
+    }
+    let done = new.with_extension("");
+    std::fs::rename(new, &done)
+        .map_err(|err| format!("Rename {:?} to {:?} fail: {}", new, done, err))?;
+    a.ms("snapshot", &done.to_string_lossy());
+
+    let snapshots = list(&dir(&a.a));
+    let times: Vec<u64> = snapshots.iter().map(|(t, _)| *t).collect();
+    let keep = retain(&times, &a.c.retention);
+    for (t, p) in snapshots.iter().filter(|(t, _)| !keep.contains(t)) {
+        a.act("rm", p, None);
+        if let Err(err) = remove_dir_all(p) {
+            a.err(format!("Remove the snapshot {} fail: {}", name(*t), err));
+        }
+    }
+    Ok(())
+}
+
+/// Print the snapshots of a host with their size, and the size of the
+/// files not linked to an older snapshot.
+pub fn snapshots(a: Addr, c: Arc<Config>) -> R {
+    let mut seen = HashSet::new();
+    for (t, p) in list(&dir(&a)) {
+        let (mut total, mut new) = (0, 0);
+        let mut queue = vec![p];
+        while let Some(d) = queue.pop() {
+            for e in read_dir(&d)
+                .map_err(|err| format!("Read {:?} fail: {}", d, err))?
+                .filter_map(|e| e.ok())
+            {
+                let m = match e.path().symlink_metadata() {
+                    Ok(m) => m,
+                    Err(_) => continue,
+                };
+                if m.is_dir() {
+                    queue.push(e.path());
+                    continue;
+                }
+                total += m.len();
+                if seen.insert((m.dev(), m.ino())) {
+                    new += m.len();
+                }
+            }
+        }
+
+        let size = format!(
+            "{} o, {} o new",
+            total.separated_string(),
+            new.separated_string()
+        );
+        if c.ansi {
+            println!(
+                "\x1b[1m{:>12} \x1b[1;34m{:x}\x1b[36m{}\x1b[0m {} ({})",
+                "snapshot",
+                a,
+                a.root,
+                name(t),
+                size
+            );
+        } else {
+            println!(
+                "{:>12}: <{:x}> {} {} ({})",
+                "snapshot",
+                a,
+                a.root,
+                name(t),
+                size
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use super::{
+    exclude, list_local, list_remote, quote, walk, Assets, Couple, Dir, Direction, Filter, Index,
+    MetaFile, Tracker, OVERHEAD, R,
+};
+use separator::Separatable;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// What to do when a host needs more room than what is free.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Space {
+    Warn,
+    Refuse,
+}
+
+impl std::str::FromStr for Space {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(Space::Warn),
+            "refuse" => Ok(Space::Refuse),
+            _ => Err(format!("Unknown check {:?}, expected warn or refuse", s)),
+        }
+    }
+}
+
+/// The available bytes of the output of `df -Pk`.
+fn parse_df(out: &str) -> Option<u64> {
+    let fields: Vec<&str> = out.lines().nth(1)?.split_whitespace().collect();
+    // The names can hold spaces, the available column is the one before
+    // the capacity.
+    let i = fields.iter().skip(4).position(|f| f.ends_with('%'))? + 4;
+    fields[i - 1].parse::<u64>().ok().map(|k| k * 1024)
+}
+#[test]
+fn test_parse_df() {
+    let out = "Filesystem     1024-blocks      Used Available Capacity Mounted on\n\
+               /dev/sda1        102400000  40000000  62400000      40% /\n";
+    assert_eq!(parse_df(out), Some(62400000 * 1024));
+    let out = "Filesystem 1024-blocks Used Available Capacity Mounted on\n\
+               my disk 100 40 60 40% /mnt/my disk\n";
+    assert_eq!(parse_df(out), Some(60 * 1024));
+    assert_eq!(parse_df("Filesystem\n"), None);
+}
+
+/// The free bytes of the local file system of `path`.
+fn local_free(path: &Path) -> Result<u64, String> {
+    let c = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| format!("Get the free space of {:?} fail: {}", path, err))?;
+    let mut s: libc::statvfs = unsafe { std::mem::zeroed() };
+    match unsafe { libc::statvfs(c.as_ptr(), &mut s) } {
+        0 => Ok(s.f_bavail as u64 * s.f_frsize as u64),
+        _ => Err(format!(
+            "Get the free space of {:?} fail: {}",
+            path,
+            std::io::Error::last_os_error()
+        )),
+    }
+}
+
+/// The free bytes of the remote file system of `path`: with the OpenSSH
+/// `fstatvfs@openssh.com` extension, or with `df`.
+fn remote_free(a: &Assets, path: &Path) -> Result<u64, String> {
+    if let Ok(s) = a.sftp.opendir(path).and_then(|mut d| d.statvfs()) {
+        return Ok(s.f_bavail * s.f_frsize);
+    }
+    let out = a.exec(&format!("df -Pk -- {}", quote(&path.to_string_lossy())))?;
+    parse_df(&out).ok_or_else(|| format!("Bad output of df for {:?}: {:?}", path, out))
+}
+
+/// The size of the files of a tree, on one side.
+fn tree_size<L>(a: &Assets, dir: &Path, filter: &Arc<Filter>, list: L) -> u64
+where
+    L: Fn(&Assets, &PathBuf) -> Result<Vec<MetaFile>, String>,
+{
+    let mut size = 0;
+    let mut queue = vec![(dir.to_path_buf(), filter.clone())];
+    while let Some((d, filter)) = queue.pop() {
+        match list(a, &d) {
+            Ok(l) => {
+                for f in exclude(l, &filter) {
+                    match f.dir {
+                        true => queue.push((d.join(&f.name), filter.enter(&f.name))),
+                        false => size += f.size,
+                    }
+                }
+            }
+            Err(err) => a.err(err),
+        }
+    }
+    size
+}
+
+/// The bytes that a download or an upload of the directories would copy.
+fn planned(a: &Assets, dirs: &[Dir], d: Direction) -> Result<u64, String> {
+    let bytes = AtomicU64::new(0);
+    for dir in dirs.iter() {
+        walk(
+            a,
+            dir.clone(),
+            |a, couple: &Couple<MetaFile>, dir, queue| {
+                let n = match (d, couple) {
+                    (_, (Some(r), Some(l))) if r.dir && l.dir => {
+                        queue.push(dir.enter(&r.name)?);
+                        0
+                    }
+                    (Direction::Download, (Some(r), l)) => {
+                        let sub = dir.enter(&r.name)?;
+                        match l {
+                            _ if r.dir => tree_size(a, &sub.remote, &sub.filter, list_remote),
+                            Some(l) if !l.dir && r.mtime < l.mtime => 0,
+                            _ => r.size,
+                        }
+                    }
+                    (Direction::Upload, (r, Some(l))) => {
+                        let sub = dir.enter(&l.name)?;
+                        match r {
+                            _ if l.dir => tree_size(a, &sub.local, &sub.filter, list_local),
+                            Some(r) if !r.dir => 0,
+                            _ => l.size + a.c.crypt.as_ref().map_or(0, |_| OVERHEAD),
+                        }
+                    }
+                    _ => 0,
+                };
+                bytes.fetch_add(n, Ordering::SeqCst);
+                Ok(())
+            },
+        )?;
+    }
+    Ok(bytes.into_inner())
+}
+
+/// Check that the destination side has room for the files to copy, with
+/// `--check-space`. The tree is compared once more before the copy.
+pub fn check_space(assets: &mut Assets, dirs: &[Dir], d: Direction, root: &Path) -> R {
+    let mode = match assets.c.check_space {
+        Some(mode) => mode,
+        None => return Ok(()),
+    };
+    // Compare quietly, without touching the index of the run.
+    assets.quiet = true;
+    let index = std::mem::replace(&mut assets.index, Tracker::new(Index::default()));
+    let need = planned(assets, dirs, d);
+    assets.quiet = false;
+    assets.index = index;
+    let need = need?;
+
+    let (free, side) = match d {
+        Direction::Download => (local_free(root)?, "local"),
+        Direction::Upload => (remote_free(assets, root)?, "remote"),
+    };
+    let ms = format!(
+        "{} o to copy, {} o free on the {} side",
+        need.separated_string(),
+        free.separated_string(),
+        side
+    );
+    match (need > free, mode) {
+        (false, _) => assets.ms("space", &ms),
+        (true, Space::Warn) => assets.ms("warning", &format!("not enough space: {}", ms)),
+        (true, Space::Refuse) => return Err(format!("Not enough space: {}", ms)),
+    }
+    Ok(())
+}
@@ -0,0 +1,204 @@
+use super::{scopes, walk, Addr, Assets, Config, Couple, Dir, Entry, MetaFile};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// A difference between the remote and the local tree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Diff {
+    OnlyLocal,
+    OnlyRemote,
+    /// Modified locally since the last run.
+    ChangedLocal,
+    /// Modified on the server since the last run.
+    ChangedRemote,
+    /// Modified on both sides since the last run.
+    ChangedBoth,
+    /// Not in the index and not the same size.
+    Size,
+    /// A file on one side, a directory on the other.
+    Type,
+}
+
+impl Diff {
+    /// The code of the difference, printed before the path.
+    fn code(self) -> &'static str {
+        match self {
+            Diff::OnlyLocal => "L",
+            Diff::OnlyRemote => "R",
+            Diff::ChangedLocal => ">",
+            Diff::ChangedRemote => "<",
+            Diff::ChangedBoth => "!",
+            Diff::Size => "S",
+            Diff::Type => "T",
+        }
+    }
+}
+
+/// The difference of the couple, none if it's the same on both sides. The
+/// mtimes aren't copied, so each side is compared with its state in the
+/// index entry of the last run, and only the sizes without an entry.
+fn diff(couple: &Couple<MetaFile>, entry: Option<&Entry>) -> Option<Diff> {
+    match couple {
+        (Some(remote), Some(local)) if remote.dir != local.dir => Some(Diff::Type),
+        (Some(remote), Some(_)) if remote.dir => None,
+        (Some(remote), Some(local)) => match entry {
+            Some(e) => match (e.remote_changed(remote), e.local_changed(local)) {
+                (true, true) => Some(Diff::ChangedBoth),
+                (true, false) => Some(Diff::ChangedRemote),
+                (false, true) => Some(Diff::ChangedLocal),
+                (false, false) => None,
+            },
+            None if remote.size != local.size => Some(Diff::Size),
+            None => None,
+        },
+        (None, None) => None,
+        (Some(_), None) => Some(Diff::OnlyRemote),
+        (None, Some(_)) => Some(Diff::OnlyLocal),
+    }
+}
+#[test]
+fn test_diff() {
+    let f = |dir: bool, size: u64, mtime: u64| MetaFile {
+        name: PathBuf::from("f"),
+        dir,
+        size,
+        mtime,
+        mode: 0,
+        owner: None,
+    };
+    // A copy is older than its source, its mtime isn't a change.
+    let e = Entry {
+        dir: false,
+        remote: (1, 10),
+        local: (1, 20),
+        hash: None,
+    };
+    let e = Some(&e);
+    assert_eq!(
+        diff(&(Some(f(false, 1, 10)), Some(f(false, 1, 20))), e),
+        None
+    );
+    assert_eq!(
+        diff(&(Some(f(true, 0, 10)), Some(f(true, 0, 20))), None),
+        None
+    );
+    assert_eq!(
+        diff(&(Some(f(false, 1, 10)), Some(f(false, 1, 30))), e),
+        Some(Diff::ChangedLocal)
+    );
+    assert_eq!(
+        diff(&(Some(f(false, 2, 10)), Some(f(false, 1, 20))), e),
+        Some(Diff::ChangedRemote)
+    );
+    assert_eq!(
+        diff(&(Some(f(false, 1, 30)), Some(f(false, 2, 20))), e),
+        Some(Diff::ChangedBoth)
+    );
+    assert_eq!(
+        diff(&(Some(f(false, 1, 10)), Some(f(false, 1, 20))), None),
+        None
+    );
+    assert_eq!(
+        diff(&(Some(f(false, 1, 10)), Some(f(false, 2, 10))), None),
+        Some(Diff::Size)
+    );
+    assert_eq!(
+        diff(&(Some(f(true, 0, 10)), Some(f(false, 0, 10))), None),
+        Some(Diff::Type)
+    );
+    assert_eq!(
+        diff(&(Some(f(false, 1, 10)), None), None),
+        Some(Diff::OnlyRemote)
+    );
+    assert_eq!(
+        diff(&(None, Some(f(true, 0, 10))), None),
+        Some(Diff::OnlyLocal)
+    );
+}
+
+/// Print the differences between the remote tree and the host directory,
+/// without modifying anything. Return true if there is any. With
+/// `porcelain`, they are printed in a stable format for scripts.
+pub fn status(a: Addr, c: Arc<Config>, porcelain: bool) -> Result<bool, String> {
+    let mut assets = Assets::new(a, c)?;
+    assets.quiet = true;
+    let root = Dir::root(
+        &assets,
+        PathBuf::from(&assets.a.root),
+        PathBuf::from(&assets.a.digest),
+    );
+    let diffs = Mutex::new(Vec::new());
+    let dirs = scopes(&assets, root)?;
+    for dir in dirs {
+        walk(&assets, dir, |_, couple, dir, queue| {
+            let name = match couple {
+                (Some(f), _) | (None, Some(f)) => &f.name,
+                (None, None) => return Ok(()),
+            };
+            let entry = assets.index.old.get(&dir.filter.path(name));
+            match diff(couple, entry) {
+                Some(d) => {
+                    let is_dir = couple
+                        .0
+                        .as_ref()
+                        .or(couple.1.as_ref())
+                        .is_some_and(|f| f.dir);
+                    let mut path = dir.filter.path(name);
+                    if is_dir && d != Diff::Type {
+                        path.push('/');
+                    }
+                    diffs.lock().unwrap().push((d, path));
+                }
+                None if couple.0.as_ref().is_some_and(|f| f.dir) => queue.push(dir.enter(name)?),
+                None => {}
+            }
+            Ok(())
+        })?;
+    }
+
+    let mut diffs = diffs.into_inner().unwrap();
+    diffs.sort_by(|x, y| x.1.cmp(&y.1));
+    let out = std::io::stdout();
+    let mut out = out.lock();
+    for (d, path) in diffs.iter() {
+        let r = match (porcelain, assets.c.ansi) {
+            (true, _) => writeln!(out, "{}\t{}\t{}", assets.a.digest, d.code(), path),
+            (false, true) => writeln!(
+                out,
+                "\x1b[1m{:>12} \x1b[1;34m{:x}\x1b[0m \x1b[{}m{}\x1b[0m {}",
+                "status",
+                assets.a,
+                match d {
+                    Diff::OnlyLocal | Diff::ChangedLocal => 32,
+                    Diff::OnlyRemote | Diff::ChangedRemote => 31,
+                    Diff::ChangedBoth | Diff::Size | Diff::Type => 33,
+                },
+                d.code(),
+                path
+            ),
+            (false, false) => writeln!(
+                out,
+                "{:>12}: <{:x}> {} {}",
+                "status",
+                assets.a,
+                d.code(),
+                path
+            ),
+        };
+        r.map_err(|err| format!("Print the status fail: {}", err))?;
+    }
+    if !porcelain {
+        drop(out);
+        assets.ms(
+            "status",
+            &match diffs.len() {
+                0 => "up to date".to_string(),
+                n => format!("{} differences", n),
+            },
+        );
+    }
+    // The differences of an incomplete comparison aren't all of them.
+    assets.result()?;
+    Ok(!diffs.is_empty())
+}
@@ -0,0 +1,76 @@
+use super::{md5_file, R, STATE};
+use std::collections::HashSet;
+use std::fs::{create_dir_all, hard_link, read_dir, rename};
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The downloaded content of all the hosts, stored once by MD5 and
+/// hardlinked into the mirrors.
+pub struct Store {
+    dir: PathBuf,
+    /// The sizes of the stored files, to ask the remote hash only if the
+    /// content can be in the store.
+    sizes: Mutex<HashSet<u64>>,
+}
+
+impl Store {
+    pub fn open() -> Result<Store, String> {
+        let dir = PathBuf::from(STATE).join("store");
+        create_dir_all(&dir).map_err(|err| format!("Create {:?} fail: {}", dir, err))?;
+        let sizes = read_dir(&dir)
+            .map_err(|err| format!("Read {:?} fail: {}", dir, err))?
+            .filter_map(|e| e.ok()?.metadata().ok())
+            .map(|m| m.len())
+            .collect();
+        Ok(Store {
+            dir,
+            sizes: Mutex::new(sizes),
+        })
+    }
+    /// The store can contain a file of this size.
+    pub fn has_size(&self, size: u64) -> bool {
+        self.sizes.lock().unwrap().contains(&size)
+    }
+    /// Put the content of the file `local` in the store, or replace it by a
+    /// link to the same content already stored.
+    pub fn add(&self, local: &Path) -> R {
+        let stored = self.dir.join(md5_file(local)?);
+        match hard_link(local, &stored) {
+            Ok(()) => {
+                let size = local.metadata().map_or(0, |m| m.len());
+                self.sizes.lock().unwrap().insert(size);
+                Ok(())
+            }
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => link(&stored, local),
+            Err(err) => Err(format!("Link {:?} to {:?} fail: {}", local, stored, err)),
+        }
+    }
+    /// Link the stored content `hash` at `local`, if it's stored with this
+    /// size. Return if it was stored.
+    pub fn get(&self, hash: &str, size: u64, local: &Path) -> Result<bool, String> {
+        let stored = self.dir.join(hash);
+        match stored.metadata() {
+            Ok(m) if m.len() == size => link(&stored, local).map(|_| true),
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Replace `dst` by a hardlink of `src`, the old content of `dst` is never
+/// modified because it can be linked elsewhere.
+fn link(src: &Path, dst: &Path) -> R {
+    if let (Ok(s), Ok(d)) = (src.metadata(), dst.metadata()) {
+        if (s.dev(), s.ino()) == (d.dev(), d.ino()) {
+            return Ok(());
+        }
+    }
+    let mut tmp = dst.as_os_str().to_os_string();
+    tmp.push(".isac-link");
+    let tmp = PathBuf::from(tmp);
+    let _ = std::fs::remove_file(&tmp);
+    hard_link(src, &tmp)
+        .and_then(|_| rename(&tmp, dst))
+        .map_err(|err| format!("Link {:?} to {:?} fail: {}", src, dst, err))
+}
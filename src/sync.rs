@@ -0,0 +1,398 @@
+use super::{
+    md5_file, mkdir_all, remote_hash, remote_hook, remote_root, remove_dir, remove_local_dir,
+    scopes, state, tree_unchanged, walk, Addr, Assets, Config, Couple, Dir, Direction, Entry,
+    Index, MetaFile, Sftp, OVERHEAD, R,
+};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// How to resolve a file modified on both sides since the last sync.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Conflict {
+    /// Rename the remote file with a `.conflict` suffix and keep both copies.
+    #[default]
+    KeepBoth,
+    /// Keep the most recently modified file.
+    Newer,
+    /// Keep the local file.
+    Local,
+    /// Keep the remote file.
+    Remote,
+}
+
+impl std::str::FromStr for Conflict {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep-both" => Ok(Conflict::KeepBoth),
+            "newer" => Ok(Conflict::Newer),
+            "local" => Ok(Conflict::Local),
+            "remote" => Ok(Conflict::Remote),
+            _ => Err(format!(
+                "Unknown conflict policy {:?}, expected keep-both, newer, local or remote",
+                s
+            )),
+        }
+    }
+}
+
+/// The state of the last sync and the one being built.
+struct State {
+    old: Index,
+    new: Mutex<Index>,
+    /// The bytes added to the remote files by the encryption.
+    overhead: u64,
+}
+
+impl State {
+    fn set(&self, path: String, entry: Entry) {
+        self.new.lock().unwrap().insert(path, entry);
+    }
+    /// Save the state of a file from the remote and the local metadata.
+    fn record(&self, path: String, remote: &MetaFile, local: &MetaFile) {
+        self.set(
+            path,
+            Entry {
+                dir: remote.dir,
+                remote: (remote.size, remote.mtime),
+                local: (local.size, local.mtime),
+                hash: None,
+            },
+        )
+    }
+    /// Save the state of a file after a transfer.
+    fn stat(&self, sftp: &Sftp, path: String, remote: &Path, local: &Path) -> R {
+        let r = sftp
+            .stat(remote)
+            .map_err(|err| format!("Stat remote file {:?} fail {}", remote, err))?;
+        let l = std::fs::metadata(local)
+            .map_err(|err| format!("Stat local file {:?} fail {}", local, err))?;
+        let mtime = l
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+
+        self.set(
+            path,
+            Entry {
+                dir: false,
+                remote: (
+                    r.size.unwrap_or(0).saturating_sub(self.overhead),
+                    r.mtime.unwrap_or(0),
+                ),
+                local: (l.len(), mtime),
+                hash: None,
+            },
+        );
+        Ok(())
+    }
+}
+
+/// Synchronize in both directions from the state of the last sync.
+pub fn sync(a: Addr, c: Arc<Config>) -> R {
+    let assets = Assets::new(a, c)?;
+    remote_hook(&assets, "pre")?;
+    let root = remote_root(&assets)?;
+    let local = PathBuf::from(&assets.a.digest);
+    create_dir_all(&local).map_err(|err| format!("Create {:?} directory fail: {}", local, err))?;
+
+    let path = state(&assets.a, "sync");
+    let s = Arc::new(State {
+        old: Index::load(&path)?,
+        new: Mutex::new(Index::default()),
+        overhead: match assets.c.crypt {
+            Some(_) => OVERHEAD,
+            None => 0,
+        },
+    });
+
+    let dirs = scopes(&assets, Dir::root(&assets, root, local))?;
+    for (p, e) in s.old.subtree("") {
+        if !dirs.iter().any(|d| d.contains(p)) {
+            s.set(p.clone(), e.clone());
+        }
+    }
+    for dir in dirs {
+        mkdir_all(&assets, &dir.remote)?;
+        create_dir_all(&dir.local)
+            .map_err(|err| format!("Create {:?} directory fail: {}", dir.local, err))?;
+        walk(&assets, dir, |a, couple, dir, queue| {
+            sync_couple(a, &s, couple, dir, queue)
+        })?;
+    }
+    s.new.lock().unwrap().save(&path)?;
+    assets.save_index()?;
+    assets.result()?;
+    remote_hook(&assets, "post")
+}
+
+fn sync_couple(
+    a: &Assets,
+    s: &Arc<State>,
+    couple: &Couple<MetaFile>,
+    dir: &Dir,
+    queue: &mut Vec<Dir>,
+) -> R {
+    let name = match couple {
+        (Some(f), _) | (None, Some(f)) => &f.name,
+        (None, None) => return Ok(()),
+    };
+    let path = dir.filter.path(name);
+    let sub = dir.enter(name)?;
+    let (r, l) = (&sub.remote, &sub.local);
+    let base = s.old.get(&path);
+
+    let result = (|| match couple {
+        (Some(remote), Some(local)) if remote.dir && local.dir => {
+            s.set(
+                path.clone(),
+                Entry {
+                    dir: true,
+                    ..Entry::default()
+                },
+            );
+            queue.push(dir.enter(name)?);
+            Ok(())
+        }
+        (Some(remote), Some(local)) if remote.dir != local.dir => match base {
+            Some(b) if b.dir == remote.dir => {
+                match remote.dir {
+                    true => remove_dir(a, r, &sub.filter)?,
+                    false => remove_remote_file(a, r)?,
+                }
+                sync_couple(a, s, &(None, Some(local.clone())), dir, queue)
+            }
+            Some(b) if b.dir == local.dir => {
+                match local.dir {
+                    true => remove_local_dir(a, l, &sub.filter)?,
+                    false => remove_local_file(a, l)?,
+                }
+                sync_couple(a, s, &(Some(remote.clone()), None), dir, queue)
+            }
+            _ => Err(format!(
+                "{:?} is a file on one side and a directory on the other",
+                r
+            )),
+        },
+        (Some(remote), Some(local)) => {
+            let (remote_changed, local_changed) = match base {
+                Some(b) => (b.remote_changed(remote), b.local_changed(local)),
+                None => {
+                    let differ = !same_content(a, dir, remote, local)?;
+                    (differ, differ)
+                }
+            };
+            match (remote_changed, local_changed) {
+                (false, false) => {
+                    s.record(path.clone(), remote, local);
+                    Ok(())
+                }
+                (true, false) => download(a, s, path.clone(), r, l, remote.size),
+                (false, true) => upload(a, s, path.clone(), r, l, local.size),
+                (true, true) => conflict(a, s, remote, local, dir),
+            }
+        }
+        (Some(remote), None) => match deleted(a, s, base, remote, &sub, true)? {
+            true => match remote.dir {
+                true => remove_dir(a, r, &sub.filter),
+                false => remove_remote_file(a, r),
+            },
+            false => match remote.dir {
+                true => {
+                    a.act("mkdir", l, None);
+                    std::fs::create_dir(l)
+                        .map_err(|err| format!("Make dir {:?} fail {}", l, err))?;
+                    s.set(
+                        path.clone(),
+                        Entry {
+                            dir: true,
+                            ..Entry::default()
+                        },
+                    );
+                    queue.push(dir.enter(name)?);
+                    Ok(())
+                }
+                false => download(a, s, path.clone(), r, l, remote.size),
+            },
+        },
+        (None, Some(local)) => match deleted(a, s, base, local, &sub, false)? {
+            true => match local.dir {
+                true => remove_local_dir(a, l, &sub.filter),
+                false => remove_local_file(a, l),
+            },
+            false => match local.dir {
+                true => {
+                    a.act("mkdir", r, None);
+                    a.sftp
+                        .mkdir(r, 0o0777)
+                        .map_err(|err| format!("Make directory {:?} fail {}", r, err))?;
+                    s.set(
+                        path.clone(),
+                        Entry {
+                            dir: true,
+                            ..Entry::default()
+                        },
+                    );
+                    queue.push(dir.enter(name)?);
+                    Ok(())
+                }
+                false => upload(a, s, path.clone(), r, l, local.size),
+            },
+        },
+        (None, None) => Ok(()),
+    })();
+
+    // Keep the old state to retry the next time.
+    if let (Err(_), Some(b)) = (&result, base) {
+        s.set(path, b.clone());
+    }
+    result
+}
+
+/// Check if a file missing on the other side was deleted there: it's
+/// unchanged on this side since the last sync, with all its sub directories.
+/// Else it's created again on the other side, a conflict if it was synced.
+fn deleted(
+    a: &Assets,
+    s: &State,
+    base: Option<&Entry>,
+    f: &MetaFile,
+    sub: &Dir,
+    remote: bool,
+) -> Result<bool, String> {
+    let unchanged = |b: &Entry, f: &MetaFile| match remote {
+        true => !b.remote_changed(f),
+        false => !b.local_changed(f),
+    };
+    let deleted = match base {
+        Some(b) if unchanged(b, f) && f.dir => tree_unchanged(a, sub, remote, |path, f| {
+            s.old.get(path).is_some_and(|b| unchanged(b, f))
+        })?,
+        Some(b) => unchanged(b, f),
+        None => false,
+    };
+    if !deleted && base.is_some() {
+        let path = match remote {
+            true => &sub.remote,
+            false => &sub.local,
+        };
+        a.log("conflict", path, None);
+    }
+    Ok(deleted)
+}
+
+fn download(a: &Assets, s: &Arc<State>, path: String, r: &Path, l: &Path, size: u64) -> R {
+    a.act("download", r, Some(size));
+    transfer(a, s, path, r, l, size, Direction::Download)
+}
+
+fn upload(a: &Assets, s: &Arc<State>, path: String, r: &Path, l: &Path, size: u64) -> R {
+    a.act("upload", r, Some(size));
+    transfer(a, s, path, r, l, size, Direction::Upload)
+}
+
+/// Copy the file on a transfer channel then save its state. If it fails,
+/// the old state is kept to retry the next time.
+fn transfer(
+    a: &Assets,
+    s: &Arc<State>,
+    path: String,
+    r: &Path,
+    l: &Path,
+    size: u64,
+    d: Direction,
+) -> R {
+    let s = s.clone();
+    let (remote, local) = (r.to_path_buf(), l.to_path_buf());
+    a.transfers.copy(
+        d,
+        remote.clone(),
+        local.clone(),
+        size,
+        move |sftp, result| {
+            let result = result.and_then(|_| s.stat(sftp, path.clone(), &remote, &local));
+            if let (Err(_), Some(b)) = (&result, s.old.get(&path)) {
+                s.set(path, b.clone());
+            }
+            result
+        },
+    );
+    Ok(())
+}
+
+fn remove_remote_file(a: &Assets, r: &PathBuf) -> R {
+    a.act("rm", r, None);
+    a.sftp
+        .unlink(r)
+        .map_err(|err| format!("Remove file {:?} fail {}", r, err))
+}
+
+fn remove_local_file(a: &Assets, l: &PathBuf) -> R {
+    a.act("rm", l, None);
+    std::fs::remove_file(l).map_err(|err| format!("rm of {:?} fail {}", l, err))
+}
+
+/// Resolve a file modified on both sides.
+fn conflict(a: &Assets, s: &Arc<State>, remote: &MetaFile, local: &MetaFile, dir: &Dir) -> R {
+    let path = dir.filter.path(&remote.name);
+    let r = dir.remote_path(&remote.name)?;
+    let l = dir.local.join(&local.name);
+    a.log("conflict", &r, None);
+
+    match a.c.conflict {
+        Conflict::Local => upload(a, s, path, &r, &l, local.size),
+        Conflict::Remote => download(a, s, path, &r, &l, remote.size),
+        Conflict::Newer if remote.mtime > local.mtime => download(a, s, path, &r, &l, remote.size),
+        Conflict::Newer => upload(a, s, path, &r, &l, local.size),
+        Conflict::KeepBoth => {
+            let name = conflict_name(a, dir, &remote.name)?;
+            let rc = dir.remote_path(&name)?;
+            let lc = dir.local.join(&name);
+
+            a.act("rename", &rc, None);
+            a.sftp
+                .rename(&r, &rc, None)
+                .map_err(|err| format!("Rename {:?} to {:?} fail {}", r, rc, err))?;
+            download(a, s, dir.filter.path(&name), &rc, &lc, remote.size)?;
+            upload(a, s, path, &r, &l, local.size)
+        }
+    }
+}
+
+/// Without the state of a last sync, a file on both sides is in sync only if
+/// it has the same content.
+fn same_content(
+    a: &Assets,
+    dir: &Dir,
+    remote: &MetaFile,
+    local: &MetaFile,
+) -> Result<bool, String> {
+    if remote.size != local.size {
+        return Ok(false);
+    }
+    let r = remote_hash(a, &dir.remote_path(&remote.name)?)?;
+    Ok(r == md5_file(&dir.local.join(&local.name))?)
+}
+
+/// The name of the conflict copy of a file: `<name>.conflict`, or
+/// `<name>.conflict.<n>` if it's taken on a side.
+fn conflict_name(a: &Assets, dir: &Dir, name: &Path) -> Result<PathBuf, String> {
+    let mut n = 0;
+    loop {
+        let mut conflict = name.as_os_str().to_os_string();
+        match n {
+            0 => conflict.push(".conflict"),
+            _ => conflict.push(format!(".conflict.{}", n)),
+        }
+        let conflict = PathBuf::from(conflict);
+        if a.sftp.lstat(&dir.remote_path(&conflict)?).is_err()
+            && std::fs::symlink_metadata(dir.local.join(&conflict)).is_err()
+        {
+            return Ok(conflict);
+        }
+        n += 1;
+    }
+}
@@ -0,0 +1,522 @@
+use super::{parse_size, print_err, Addr, Assets, Bucket, Config, Crypt, Key, Limit, Sftp, R};
+use md5::{Digest, Md5};
+use ssh2::{FileStat, OpenFlags, OpenType};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use threadpool::ThreadPool;
+
+/// The direction of a copy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+/// The SFTP channels of one host. Each channel has its own SSH session,
+/// so the transfers don't wait for each other.
+struct Channels {
+    a: Addr,
+    key: Key,
+    ansi: bool,
+    /// The errors of the host, the failed transfers are counted.
+    errors: Arc<AtomicU64>,
+    /// The channels not used by a transfer.
+    idle: Mutex<Vec<Arc<Sftp>>>,
+    free: Condvar,
+    /// A connection failed, use only the opened channels.
+    full: AtomicBool,
+}
+
+impl Channels {
+    /// Take an idle channel, or open a new one. There are never more
+    /// running transfers than workers, so never more channels than workers.
+    fn take(&self) -> Arc<Sftp> {
+        if let Some(sftp) = self.idle.lock().unwrap().pop() {
+            return sftp;
+        }
+        if !self.full.load(Ordering::SeqCst) {
+            match Assets::connect(&self.a, &self.key) {
+                Ok(sftp) => return Arc::new(sftp),
+                Err(err) => {
+                    self.full.store(true, Ordering::SeqCst);
+                    print_err(
+                        format!("{}, continue with the opened channels", err),
+                        &self.a,
+                        self.ansi,
+                    );
+                }
+            }
+        }
+        // The main channel is always opened, wait for one.
+        let mut idle = self.idle.lock().unwrap();
+        loop {
+            match idle.pop() {
+                Some(sftp) => return sftp,
+                None => idle = self.free.wait(idle).unwrap(),
+            }
+        }
+    }
+    fn give_back(&self, sftp: Arc<Sftp>) {
+        self.idle.lock().unwrap().push(sftp);
+        self.free.notify_one();
+    }
+}
+
+/// The number of transfers queued or running, limited so that the walk
+/// waits for the workers instead of queuing the whole tree.
+struct Pending {
+    count: Mutex<usize>,
+    done: Condvar,
+    max: usize,
+}
+
+impl Pending {
+    /// Wait for a free place in the queue and take it.
+    fn enter(&self) {
+        let mut count = self.count.lock().unwrap();
+        while *count >= self.max {
+            count = self.done.wait(count).unwrap();
+        }
+        *count += 1;
+    }
+    fn leave(&self) {
+        *self.count.lock().unwrap() -= 1;
+        self.done.notify_one();
+    }
+}
+
+/// The workers that copy the files of one host.
+pub struct Transfers {
+    pool: ThreadPool,
+    pending: Arc<Pending>,
+    channels: Arc<Channels>,
+    limit: Arc<Limit>,
+    /// The files from this size are copied in chunks.
+    threshold: u64,
+    chunk: u64,
+    /// Encrypt the uploaded files and decrypt the downloaded ones.
+    crypt: Option<Arc<Crypt>>,
+    /// The local paths of the failed copies, not to record in the index.
+    failed: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl Transfers {
+    /// Start the workers, the first one use the main channel `sftp`. The
+    /// transfers are limited by the global limit and the `bwlimit` option.
+    pub fn new(
+        a: &Addr,
+        c: &Config,
+        sftp: Arc<Sftp>,
+        errors: Arc<AtomicU64>,
+    ) -> Result<Transfers, String> {
+        let mut buckets: Vec<Arc<Bucket>> = c.bwlimit.iter().cloned().collect();
+        for v in a.option("bwlimit") {
+            buckets.push(Arc::new(Bucket::new(parse_size(v)?)));
+        }
+        Ok(Transfers {
+            pool: ThreadPool::new(c.transfers.max(1)),
+            pending: Arc::new(Pending {
+                count: Mutex::new(0),
+                done: Condvar::new(),
+                max: 2 * c.transfers.max(1),
+            }),
+            channels: Arc::new(Channels {
+                a: a.clone(),
+                key: c.key.clone(),
+                ansi: c.ansi,
+                errors,
+                idle: Mutex::new(vec![sftp]),
+                free: Condvar::new(),
+                full: AtomicBool::new(false),
+            }),
+            limit: Arc::new(Limit::new(buckets)),
+            threshold: c.chunk_threshold,
+            chunk: c.chunk_size,
+            crypt: c.crypt.clone(),
+            failed: Arc::new(Mutex::new(HashSet::new())),
+        })
+    }
+    /// Run the transfer `f` on a free channel, the error is printed. Wait
+    /// while twice as many transfers as workers are queued.
+    pub fn spawn<F>(&self, f: F)
+    where
+        F: FnOnce(&Sftp) -> R + Send + 'static,
+    {
+        let channels = self.channels.clone();
+        let pending = self.pending.clone();
+        pending.enter();
+        self.pool.execute(move || {
+            let sftp = channels.take();
+            let r = f(&sftp);
+            channels.give_back(sftp);
+            pending.leave();
+            if let Err(err) = r {
+                channels.errors.fetch_add(1, Ordering::SeqCst);
+                print_err(err, &channels.a, channels.ansi);
+            }
+        });
+    }
+    /// Copy a file of `size` bytes, then call `then` with the result of the
+    /// copy. A large file is split in chunks copied at the same time, except
+    /// when it's encrypted.
+    pub fn copy<F>(&self, d: Direction, remote: PathBuf, local: PathBuf, size: u64, then: F)
+    where
+        F: FnOnce(&Sftp, R) -> R + Send + 'static,
+    {
+        let (failed, path) = (self.failed.clone(), local.clone());
+        let then = move |sftp: &Sftp, r: R| {
+            let r = then(sftp, r);
+            if r.is_err() {
+                failed.lock().unwrap().insert(path);
+            }
+            r
+        };
+        if self.chunk == 0 || size < self.threshold || size <= self.chunk || self.crypt.is_some() {
+            let limit = self.limit.clone();
+            let crypt = self.crypt.clone();
+            return self.spawn(move |sftp| {
+                let crypt = crypt.as_deref();
+                let r = match d {
+                    Direction::Download => download_file(sftp, &limit, crypt, &remote, &local),
+                    Direction::Upload => upload_file(sftp, &limit, crypt, &remote, &local),
+                };
+                then(sftp, r)
+            });
+        }
+
+        if d == Direction::Download {
+            if let Err(err) = unlink(&local) {
+                return self.spawn(move |sftp| then(sftp, Err(err)));
+            }
+        }
+        let count = size.div_ceil(self.chunk);
+        let file = Arc::new(Chunked {
+            d,
+            remote,
+            local,
+            size,
+            limit: self.limit.clone(),
+            state: Mutex::new((count, Ok(()), Some(then))),
+        });
+        for i in 0..count {
+            let file = file.clone();
+            let offset = i * self.chunk;
+            let len = self.chunk.min(size - offset);
+            self.spawn(move |sftp| {
+                let r = file.copy_chunk(sftp, offset, len);
+                file.done(sftp, r)
+            });
+        }
+    }
+    /// Copy the file `from` of another host, read on `src`, to `to` on this
+    /// host, without a local copy.
+    pub fn relay(&self, src: Arc<Sftp>, from: PathBuf, to: PathBuf) {
+        let limit = self.limit.clone();
+        self.spawn(move |sftp| {
+            std::io::copy(
+                &mut limit.reader(
+                    src.open(&from)
+                        .map_err(|err| format!("Open source file {:?} fail {}", from, err))?,
+                ),
+                &mut sftp
+                    .create(&to)
+                    .map_err(|err| format!("Create remote file {:?} fail {}", to, err))?,
+            )
+            .map_err(|err| format!("Copy {:?} to {:?} fail {}", from, to, err))
+            .map(|_| ())
+        });
+    }
+    /// Wait for the end of all the transfers.
+    pub fn join(&self) {
+        self.pool.join();
+    }
+    /// Take the local paths of the copies that failed until now.
+    pub fn failed(&self) -> HashSet<PathBuf> {
+        std::mem::take(&mut *self.failed.lock().unwrap())
+    }
+    /// The bytes transferred until now.
+    pub fn bytes(&self) -> u64 {
+        self.limit.bytes()
+    }
+}
+
+/// A large file copied in chunks.
+struct Chunked<F> {
+    d: Direction,
+    remote: PathBuf,
+    local: PathBuf,
+    size: u64,
+    limit: Arc<Limit>,
+    /// The number of chunks not yet copied, the first error, and what to
+    /// do after the last chunk.
+    state: Mutex<(u64, R, Option<F>)>,
+}
+
+impl<F> Chunked<F>
+where
+    F: FnOnce(&Sftp, R) -> R,
+{
+    /// Copy `len` bytes from `offset`. The file is not truncated, to keep
+    /// the chunks already written; a downloaded file is a new one, removed
+    /// before the first chunk.
+    fn copy_chunk(&self, sftp: &Sftp, offset: u64, len: u64) -> R {
+        let (r, l) = (&self.remote, &self.local);
+        match self.d {
+            Direction::Download => {
+                let src = sftp
+                    .open(r)
+                    .map_err(|err| format!("Open remote file {:?} fail {}", r, err))?;
+                let dst = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(l)
+                    .map_err(|err| format!("Open local file {:?} fail {}", l, err))?;
+                self.copy_range(src, dst, offset, len)
+            }
+            Direction::Upload => {
+                let src =
+                    File::open(l).map_err(|err| format!("Open local file {:?} fail {}", l, err))?;
+                let dst = sftp
+                    .open_mode(
+                        r,
+                        OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE,
+                        0o644,
+                        OpenType::File,
+                    )
+                    .map_err(|err| format!("Open remote file {:?} fail {}", r, err))?;
+                self.copy_range(src, dst, offset, len)
+            }
+        }
+    }
+    /// Copy the chunk from `src` to `dst`, then read it back from `dst`: its
+    /// MD5 must be the one of the bytes read from `src`. Reading back an
+    /// upload counts in the bandwidth limit.
+    fn copy_range<S, D>(&self, mut src: S, mut dst: D, offset: u64, len: u64) -> R
+    where
+        S: Read + Seek,
+        D: Read + Write + Seek,
+    {
+        let r = &self.remote;
+        let mut hashed = Hashed {
+            inner: &mut dst,
+            hasher: Md5::new(),
+        };
+        let copied = src
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| hashed.inner.seek(SeekFrom::Start(offset)))
+            .and_then(|_| std::io::copy(&mut self.limit.reader(src.take(len)), &mut hashed))
+            .map_err(|err| format!("Copy {:?} at {} fail {}", r, offset, err))?;
+        if copied != len {
+            return Err(format!(
+                "Copy {:?} at {}: {} bytes copied instead of {}",
+                r, offset, copied, len
+            ));
+        }
+        let written = hashed.hasher.finalize();
+
+        let mut hasher = Md5::new();
+        dst.seek(SeekFrom::Start(offset))
+            .and_then(|_| {
+                let mut back = (&mut dst).take(len);
+                match self.d {
+                    Direction::Download => std::io::copy(&mut back, &mut hasher),
+                    Direction::Upload => std::io::copy(&mut self.limit.reader(back), &mut hasher),
+                }
+            })
+            .map_err(|err| format!("Read back {:?} at {} fail {}", r, offset, err))?;
+        match hasher.finalize() == written {
+            true => Ok(()),
+            false => Err(format!(
+                "Verify {:?} at {} fail: the chunk read back differs from the source",
+                r, offset
+            )),
+        }
+    }
+    /// Count a copied chunk, the last one truncates, verifies and
+    /// calls `then`.
+    fn done(&self, sftp: &Sftp, r: R) -> R {
+        let (r, then) = {
+            let mut state = self.state.lock().unwrap();
+            state.0 -= 1;
+            if state.1.is_ok() {
+                state.1 = r;
+            }
+            match state.0 {
+                0 => (std::mem::replace(&mut state.1, Ok(())), state.2.take()),
+                _ => (Ok(()), None),
+            }
+        };
+        match then {
+            Some(then) => {
+                let r = r.and_then(|_| self.verify(sftp));
+                if r.is_err() {
+                    self.remove_partial(sftp);
+                }
+                then(sftp, r)
+            }
+            None => Ok(()),
+        }
+    }
+    /// Check the size of both sides once all the chunks are copied: the
+    /// source must not have changed and every byte must be written. The
+    /// destination is then truncated, it may be an older larger file.
+    fn verify(&self, sftp: &Sftp) -> R {
+        let (r, l) = (&self.remote, &self.local);
+        let remote = sftp
+            .stat(r)
+            .map_err(|err| format!("Stat remote file {:?} fail {}", r, err))?
+            .size
+            .unwrap_or(0);
+        let local = std::fs::metadata(l)
+            .map_err(|err| format!("Stat local file {:?} fail {}", l, err))?
+            .len();
+        let (src, dst) = match self.d {
+            Direction::Download => (remote, local),
+            Direction::Upload => (local, remote),
+        };
+        if src != self.size || dst < self.size {
+            return Err(format!(
+                "Verify {:?} fail: {} bytes on the remote and {} bytes on the local, expected {}",
+                r, remote, local, self.size
+            ));
+        }
+        if dst == self.size {
+            return Ok(());
+        }
+        match self.d {
+            Direction::Download => OpenOptions::new()
+                .write(true)
+                .open(l)
+                .and_then(|f| f.set_len(self.size))
+                .map_err(|err| format!("Truncate local file {:?} fail {}", l, err)),
+            Direction::Upload => sftp
+                .setstat(
+                    r,
+                    FileStat {
+                        size: Some(self.size),
+                        uid: None,
+                        gid: None,
+                        perm: None,
+                        atime: None,
+                        mtime: None,
+                    },
+                )
+                .map_err(|err| format!("Truncate remote file {:?} fail {}", r, err)),
+        }
+    }
+    /// Remove the destination of a failed copy, it's copied again next time.
+    fn remove_partial(&self, sftp: &Sftp) {
+        match self.d {
+            Direction::Download => std::fs::remove_file(&self.local).is_ok(),
+            Direction::Upload => sftp.unlink(&self.remote).is_ok(),
+        };
+    }
+}
+
+#[test]
+fn test_copy_range() {
+    use std::io::Cursor;
+
+    let file: Chunked<fn(&Sftp, R) -> R> = Chunked {
+        d: Direction::Download,
+        remote: PathBuf::from("r"),
+        local: PathBuf::from("l"),
+        size: 10,
+        limit: Arc::new(Limit::default()),
+        state: Mutex::new((1, Ok(()), None)),
+    };
+    let mut dst = Cursor::new(b"----------".to_vec());
+    assert!(file
+        .copy_range(Cursor::new(b"0123456789"), &mut dst, 4, 3)
+        .is_ok());
+    assert_eq!(dst.get_ref(), b"----456---");
+    assert!(file
+        .copy_range(Cursor::new(b"0123"), &mut dst, 2, 3)
+        .is_err());
+}
+
+/// A writer that hashes the bytes written.
+struct Hashed<W> {
+    inner: W,
+    hasher: Md5,
+}
+
+impl<W: Write> Write for Hashed<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn upload_file(
+    sftp: &Sftp,
+    limit: &Limit,
+    crypt: Option<&Crypt>,
+    remote_path: &PathBuf,
+    local_path: &PathBuf,
+) -> R {
+    let mut local_file = limit.reader(
+        File::open(local_path)
+            .map_err(|err| format!("Open local file {:?} fail {}", local_path, err))?,
+    );
+    let mut remote_file = sftp
+        .create(remote_path)
+        .map_err(|err| format!("Create remote file {:?} fail {:?}", remote_path, err))?;
+    match crypt {
+        Some(c) => c.encrypt(&mut local_file, &mut remote_file),
+        None => std::io::copy(&mut local_file, &mut remote_file),
+    }
+    .map_err(|err| format!("Copy of {:?} fail {}", remote_path, err))
+    .map(|_| ())
+}
+
+/// Remove the local file before a download, it's never written in place:
+/// it can be linked in the store, in a snapshot or in the tree of another
+/// host.
+fn unlink(local: &Path) -> R {
+    match std::fs::remove_file(local) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            Err(format!("Remove {:?} fail: {}", local, err))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn download_file(
+    sftp: &Sftp,
+    limit: &Limit,
+    crypt: Option<&Crypt>,
+    remote_path: &PathBuf,
+    local_path: &PathBuf,
+) -> R {
+    let mut remote_file = limit.reader(
+        sftp.open(remote_path)
+            .map_err(|err| format!("Open remote file {:?} fail {}", remote_path, err))?,
+    );
+
+    unlink(local_path)?;
+    let mut local_file = File::create(local_path)
+        .map_err(|err| format!("Create local file {:?} fail {}", local_path, err))?;
+
+    let r = match crypt {
+        Some(c) => c.decrypt(&mut remote_file, &mut local_file),
+        None => std::io::copy(&mut remote_file, &mut local_file),
+    };
+    if let Err(err) = r {
+        // Never keep a file not authenticated.
+        let _ = std::fs::remove_file(local_path);
+        return Err(format!("Copy {:?} fail {}", remote_path, err));
+    }
+
+    Ok(())
+}
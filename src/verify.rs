@@ -0,0 +1,170 @@
+use super::{md5_file, quote, scopes, walk, Addr, Assets, Config, Dir, MetaFile};
+use md5::{Digest, Md5};
+use openssl::rand::rand_bytes;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Check if the content of the file is verified, for `sample` percent of
+/// the files. The choice depends on the seed of the run.
+fn sampled(seed: &[u8], path: &str, sample: f64) -> bool {
+    if sample >= 100.0 {
+        return true;
+    }
+    let h = Md5::new().chain(seed).chain(path.as_bytes()).finalize();
+    let n = u32::from_be_bytes([h[0], h[1], h[2], h[3]]);
+    (n as f64) < sample / 100.0 * (u32::MAX as f64 + 1.0)
+}
+#[test]
+fn test_sampled() {
+    let paths: Vec<String> = (0..1000).map(|i| format!("dir/{}", i)).collect();
+    let count = |seed: &[u8], sample| paths.iter().filter(|p| sampled(seed, p, sample)).count();
+    assert_eq!(count(b"seed", 100.0), 1000);
+    assert_eq!(count(b"seed", 0.0), 0);
+    let half = count(b"seed", 50.0);
+    assert!(half > 400 && half < 600, "{}", half);
+    assert_ne!(
+        paths.iter().position(|p| sampled(b"seed", p, 10.0)),
+        paths.iter().position(|p| sampled(b"other", p, 10.0))
+    );
+}
+
+/// The MD5 of the content of a remote file: with `md5sum`, or read and
+/// decrypted if it's encrypted.
+pub fn remote_hash(a: &Assets, remote: &Path) -> Result<String, String> {
+    let hash = match &a.c.crypt {
+        None => {
+            let out = a.exec(&format!("md5sum -- {}", quote(&remote.to_string_lossy())))?;
+            match out.split_whitespace().next() {
+                Some(h) if h.len() == 32 && h.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                    return Ok(h.to_ascii_lowercase())
+                }
+                _ => return Err(format!("Bad output of md5sum for {:?}: {:?}", remote, out)),
+            }
+        }
+        Some(c) => {
+            let mut f = a
+                .sftp
+                .open(remote)
+                .map_err(|err| format!("Open remote file {:?} fail {}", remote, err))?;
+            let mut hasher = Md5::new();
+            c.decrypt(&mut f, &mut hasher)
+                .map_err(|err| format!("Read remote file {:?} fail {}", remote, err))?;
+            hasher.finalize()
+        }
+    };
+    Ok(hash.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// The problems found, and the files compared.
+#[derive(Default)]
+struct Counts {
+    problems: AtomicU64,
+    files: AtomicU64,
+    hashed: AtomicU64,
+}
+
+impl Counts {
+    fn report(&self, a: &Assets, kind: &str, path: &str, detail: &str) {
+        self.problems.fetch_add(1, Ordering::SeqCst);
+        if a.c.ansi {
+            println!(
+                "\x1b[1;31m{:>12} \x1b[1;34m{:x}\x1b[0m {}{}",
+                kind, a.a, path, detail
+            );
+        } else {
+            println!("{:>12}: <{:x}> {}{}", kind, a.a, path, detail);
+        }
+    }
+}
+
+/// Compare two files of the same path.
+fn verify_file(
+    a: &Assets,
+    counts: &Counts,
+    (seed, sample): (&[u8], f64),
+    dir: &Dir,
+    remote: &MetaFile,
+    local: &MetaFile,
+) {
+    let path = dir.filter.path(&remote.name);
+    counts.files.fetch_add(1, Ordering::SeqCst);
+    if remote.size != local.size {
+        let detail = format!(" ({} o remote, {} o local)", remote.size, local.size);
+        return counts.report(a, "size", &path, &detail);
+    }
+    if remote.symlink() || local.symlink() || !sampled(seed, &path, sample) {
+        return;
+    }
+    counts.hashed.fetch_add(1, Ordering::SeqCst);
+    let local_path = dir.local.join(&local.name);
+    match (
+        dir.remote_path(&remote.name)
+            .and_then(|r| remote_hash(a, &r)),
+        md5_file(&local_path),
+    ) {
+        (Ok(r), Ok(l)) if r != l => {
+            counts.report(a, "hash", &path, &format!(" ({} remote, {} local)", r, l))
+        }
+        (Ok(_), Ok(_)) => {}
+        (Err(err), _) | (_, Err(err)) => {
+            counts.report(a, "unreadable", &path, &format!(": {}", err))
+        }
+    }
+}
+
+/// Compare the size and the content of every file of the remote
+/// tree and the host directory, without modifying anything. Return true if
+/// there is any problem. Only the content of `sample` percent of the files
+/// is compared.
+pub fn verify(a: Addr, c: Arc<Config>, sample: f64) -> Result<bool, String> {
+    let mut assets = Assets::new(a, c)?;
+    assets.quiet = true;
+    let root = Dir::root(
+        &assets,
+        PathBuf::from(&assets.a.root),
+        PathBuf::from(&assets.a.digest),
+    );
+    let dirs = scopes(&assets, root)?;
+    let mut seed = [0u8; 16];
+    rand_bytes(&mut seed).map_err(|err| format!("Generate the sample seed fail: {}", err))?;
+
+    let counts = Counts::default();
+    for dir in dirs {
+        walk(&assets, dir, |a, couple, dir, queue| {
+            match couple {
+                (Some(remote), Some(local)) if remote.dir && local.dir => {
+                    queue.push(dir.enter(&remote.name)?)
+                }
+                (Some(remote), Some(local)) if remote.dir != local.dir => {
+                    counts.report(a, "type", &dir.filter.path(&remote.name), "")
+                }
+                (Some(remote), Some(local)) => {
+                    verify_file(a, &counts, (&seed, sample), dir, remote, local)
+                }
+                (Some(remote), None) => {
+                    counts.report(a, "missing", &dir.filter.path(&remote.name), " (local)")
+                }
+                (None, Some(local)) => {
+                    counts.report(a, "missing", &dir.filter.path(&local.name), " (remote)")
+                }
+                (None, None) => {}
+            }
+            Ok(())
+        })?;
+    }
+
+    let errors = assets.errors.load(Ordering::SeqCst);
+    let problems = counts.problems.load(Ordering::SeqCst);
+    assets.ms(
+        "verify",
+        &format!(
+            "{} files, {} hashed, {} problems, {} errors",
+            counts.files.load(Ordering::SeqCst),
+            counts.hashed.load(Ordering::SeqCst),
+            problems,
+            errors
+        ),
+    );
+    Ok(problems + errors > 0)
+}
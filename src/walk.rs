@@ -0,0 +1,656 @@
+use super::{linkvec, Assets, Couple, Crypt, Filter, MetaFile, IGNORE, OVERHEAD, R};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fs::read_dir;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// A directory to compare, on the remote and the local side.
+#[derive(Clone)]
+pub struct Dir {
+    pub remote: PathBuf,
+    pub local: PathBuf,
+    /// The filter of this directory, with its `.isacignore` once listed.
+    pub filter: Arc<Filter>,
+    /// The depth from the root, the root is 0.
+    pub depth: usize,
+    /// The cipher of the remote names, if they are encrypted.
+    pub names: Option<Arc<Crypt>>,
+    /// Compare only this entry of the directory, for `--path`.
+    pub only: Option<PathBuf>,
+}
+
+impl Dir {
+    pub fn root(a: &Assets, remote: PathBuf, local: PathBuf) -> Dir {
+        Dir {
+            remote,
+            local,
+            filter: Filter::new(&a.c, &a.a),
+            depth: 0,
+            names: a.c.crypt.clone().filter(|_| a.c.encrypt_names),
+            only: None,
+        }
+    }
+    /// The sub directory `name`.
+    pub fn enter(&self, name: &Path) -> Result<Dir, String> {
+        Ok(Dir {
+            remote: self.remote_path(name)?,
+            local: self.local.join(name),
+            filter: self.filter.enter(name),
+            depth: self.depth + 1,
+            names: self.names.clone(),
+            only: None,
+        })
+    }
+    /// The remote path of the file `name` of the directory.
+    pub fn remote_path(&self, name: &Path) -> Result<PathBuf, String> {
+        Ok(match &self.names {
+            Some(c) => self.remote.join(c.encrypt_name(name)?),
+            None => self.remote.join(name),
+        })
+    }
+    /// The parent directory of `path`, relative to this one, that compares
+    /// only its entry. The ignore files of the parents are read on the way,
+    /// none if the path is excluded.
+    pub fn scope(&self, a: &Assets, path: &Path) -> Result<Option<Dir>, String> {
+        let mut names = Vec::new();
+        for c in path.components() {
+            match c {
+                Component::Normal(name) => names.push(Path::new(name)),
+                Component::CurDir => {}
+                _ => return Err(format!("The path {:?} must be relative to the root", path)),
+            }
+        }
+        let mut dir = self.clone();
+        let last = match names.pop() {
+            Some(last) => last,
+            None => return Ok(Some(dir)),
+        };
+        for name in names {
+            dir.filter = ignore_file(a, &dir, remote_ignore(a, &dir));
+            if dir.filter.excluded(name, true) {
+                return Ok(None);
+            }
+            dir = dir.enter(name)?;
+        }
+        // The ignore file of the last directory is read again by the walk.
+        let is_dir = match dir.local.join(last).symlink_metadata() {
+            Ok(m) => m.is_dir(),
+            Err(_) => dir
+                .remote_path(last)
+                .is_ok_and(|r| a.sftp.lstat(&r).is_ok_and(|s| s.is_dir())),
+        };
+        if ignore_file(a, &dir, remote_ignore(a, &dir)).excluded(last, is_dir) {
+            return Ok(None);
+        }
+        dir.only = Some(last.to_path_buf());
+        Ok(Some(dir))
+    }
+    /// Check if the path of the index is compared from this directory.
+    pub fn contains(&self, path: &str) -> bool {
+        let scope = match &self.only {
+            Some(name) => self.filter.path(name),
+            None => self.filter.dir().to_string(),
+        };
+        scope.is_empty() || path == scope || path.starts_with(&format!("{}/", scope))
+    }
+}
+
+/// The directories to compare from the root: the parents of the `--path`
+/// options with their entry, or the root itself. The entries of the index
+/// out of these paths are kept as they were.
+pub fn scopes(a: &Assets, root: Dir) -> Result<Vec<Dir>, String> {
+    if a.c.paths.is_empty() {
+        return Ok(vec![root]);
+    }
+    let mut dirs = Vec::new();
+    for path in a.c.paths.iter() {
+        match root.scope(a, path)? {
+            Some(dir) => dirs.push(dir),
+            None => return Err(format!("The path {:?} is excluded", path)),
+        }
+    }
+    a.index.keep_if(|p| !dirs.iter().any(|d| d.contains(p)));
+    Ok(dirs)
+}
+
+/// The number of modified entries waiting for the transfers, before
+/// waiting for them to record their new metadata.
+const PENDING: usize = 10_000;
+
+/// Compare the directory and all its sub directories, depth first. The
+/// sub directories are pushed in a queue by `m` instead of a recursion, so
+/// only the listing of one directory at a time is in memory.
+pub fn walk<M>(a: &Assets, root: Dir, m: M) -> R
+where
+    M: Fn(&Assets, &Couple<MetaFile>, &Dir, &mut Vec<Dir>) -> R,
+{
+    let mut queue = Vec::new();
+    let mut changed = Vec::new();
+    let r = compare_dir(a, root, &mut queue, &mut changed, &m);
+    if r.is_ok() {
+        while let Some(dir) = queue.pop() {
+            if let Err(err) = compare_dir(a, dir, &mut queue, &mut changed, &m) {
+                a.err(err);
+            }
+            if changed.iter().map(|(_, names)| names.len()).sum::<usize>() >= PENDING {
+                record_changed(a, &mut changed);
+            }
+        }
+    }
+    record_changed(a, &mut changed);
+    r
+}
+
+/// Get the new metadata of the modified entries once the transfers are
+/// done, with one stat by entry instead of listing their directory. The
+/// entries whose copy failed keep their state of the last run, to be copied
+/// again.
+fn record_changed(a: &Assets, changed: &mut Vec<(Dir, Vec<PathBuf>)>) {
+    a.transfers.join();
+    let failed = a.transfers.failed();
+    for (dir, names) in changed.drain(..) {
+        let couples: Vec<Couple<MetaFile>> = names
+            .into_iter()
+            .filter(|name| match failed.contains(&dir.local.join(name)) {
+                true => {
+                    a.index.keep(&dir.filter.path(name));
+                    false
+                }
+                false => true,
+            })
+            .map(|name| (stat_remote(a, &dir, &name), stat_local(&dir, &name)))
+            .collect();
+        record(a, &dir, &couples);
+    }
+}
+
+/// The remote entry `name` of the directory after a modification, with
+/// the size before the encryption, if it exists.
+fn stat_remote(a: &Assets, dir: &Dir, name: &Path) -> Option<MetaFile> {
+    let p = dir.remote_path(name).ok()?;
+    let stat = a.sftp.lstat(&p).ok()?;
+    let mut f = MetaFile::try_from(&(p, stat)).ok()?;
+    f.name = name.to_path_buf();
+    if a.c.crypt.is_some() && !f.dir {
+        f.size = f.size.saturating_sub(OVERHEAD);
+    }
+    Some(f)
+}
+
+/// The local entry `name` of the directory, if it exists.
+fn stat_local(dir: &Dir, name: &Path) -> Option<MetaFile> {
+    let info = dir.local.join(name).symlink_metadata().ok()?;
+    MetaFile::local(name.to_path_buf(), &info).ok()
+}
+
+fn record(a: &Assets, dir: &Dir, couples: &[Couple<MetaFile>]) {
+    let hash = match a.c.hash {
+        true => Some(dir.local.as_path()),
+        false => None,
+    };
+    a.index.record(dir.filter.dir(), couples, hash);
+}
+
+fn compare_dir<M>(
+    a: &Assets,
+    mut dir: Dir,
+    queue: &mut Vec<Dir>,
+    changed: &mut Vec<(Dir, Vec<PathBuf>)>,
+    m: &M,
+) -> R
+where
+    M: Fn(&Assets, &Couple<MetaFile>, &Dir, &mut Vec<Dir>) -> R,
+{
+    if a.c.max_depth.is_some_and(|max| dir.depth > max) {
+        a.log("skip", &dir.remote, None);
+        a.index.keep(dir.filter.dir());
+        return Ok(());
+    }
+    a.log("index", &dir.remote, None);
+
+    let remote_list = decrypt_list(a, &dir, list_remote(a, &dir.remote)?);
+    let local_list = list_local(a, &dir.local)?;
+
+    dir.filter = ignore_file(a, &dir, has_ignore(&remote_list));
+    let remote_list = select(remote_list, &dir);
+    let local_list = select(local_list, &dir);
+
+    // The other entries of a directory of `--path` are not compared.
+    if dir.only.is_none() {
+        for (list, path, remote) in [
+            (&remote_list, &dir.remote, true),
+            (&local_list, &dir.local, false),
+        ] {
+            for name in a.index.observe(dir.filter.dir(), list, remote) {
+                if a.c.changes {
+                    a.log("changed", &path.join(name), None);
+                }
+            }
+        }
+    }
+
+    // The entries that a side was modified for are recorded at the end.
+    let mut names = Vec::new();
+    let mut kept = Vec::new();
+    for couple in linkvec(remote_list, local_list) {
+        let actions = a.actions.load(Ordering::SeqCst);
+        if let Err(err) = m(a, &couple, &dir, queue) {
+            a.err(err);
+        }
+        match (a.actions.load(Ordering::SeqCst) == actions, &couple) {
+            (true, _) => kept.push(couple),
+            (false, (Some(f), _)) | (false, (None, Some(f))) => names.push(f.name.clone()),
+            (false, (None, None)) => {}
+        }
+    }
+    record(a, &dir, &kept);
+    if !names.is_empty() {
+        changed.push((dir, names));
+    }
+    Ok(())
+}
+
+/// List the files of a remote directory. The whole directory is read before
+/// it's compared: the entries of both sides are sorted to pair them, and
+/// SFTP returns them in any order. A known limitation: a directory with
+/// millions of entries is in memory at once.
+pub fn list_remote(a: &Assets, remote_dir: &PathBuf) -> Result<Vec<MetaFile>, String> {
+    let mut d = a
+        .sftp
+        .opendir(remote_dir)
+        .map_err(|err| format!("Index remote directory {:?} fail: {}", remote_dir, err))?;
+
+    let mut list = Vec::new();
+    loop {
+        let (name, stat) = match d.readdir() {
+            Ok(entry) => entry,
+            // No more files
+            Err(err) if err.code() == -16 => break,
+            Err(err) => {
+                return Err(format!(
+                    "Index remote directory {:?} fail: {}",
+                    remote_dir, err
+                ))
+            }
+        };
+        if name == Path::new(".") || name == Path::new("..") {
+            continue;
+        }
+        match MetaFile::try_from(&(remote_dir.join(name), stat)) {
+            Ok(meta) => list.push(meta),
+            Err(err) => a.err(format!(
+                "In indexing remote directory {:?}: {}",
+                remote_dir, err
+            )),
+        }
+    }
+    Ok(list)
+}
+
+/// The remote list with the names and the sizes of the files before their
+/// encryption. The files whose name can't be decrypted are skipped.
+fn decrypt_list(a: &Assets, dir: &Dir, list: Vec<MetaFile>) -> Vec<MetaFile> {
+    if a.c.crypt.is_none() {
+        return list;
+    }
+    list.into_iter()
+        .filter_map(|mut f| {
+            if !f.dir {
+                f.size = f.size.saturating_sub(OVERHEAD);
+            }
+            if let Some(c) = &dir.names {
+                match c.decrypt_name(&f.name) {
+                    Some(name) => f.name = name,
+                    None => {
+                        a.log("skip", &dir.remote.join(&f.name), None);
+                        return None;
+                    }
+                }
+            }
+            Some(f)
+        })
+        .collect()
+}
+
+/// List the files of a local directory.
+pub fn list_local(a: &Assets, local_dir: &PathBuf) -> Result<Vec<MetaFile>, String> {
+    Ok(read_dir(local_dir)
+        .map_err(|err| format!("Index local directory {:?} fail: {}", local_dir, err))?
+        .filter_map(|r| match r {
+            Ok(f) => Some(f),
+            Err(err) => {
+                a.err(format!(
+                    "In indexing local directory {:?}: {}",
+                    local_dir, err
+                ));
+                None
+            }
+        })
+        .filter_map(|f| match MetaFile::try_from(f) {
+            Ok(meta) => Some(meta),
+            Err(err) => {
+                a.err(format!(
+                    "In indexing local directory {:?}: {}",
+                    local_dir, err
+                ));
+                None
+            }
+        })
+        .collect())
+}
+
+/// Remove the excluded files of the list, and the entries not compared
+/// for `--path`.
+fn select(list: Vec<MetaFile>, dir: &Dir) -> Vec<MetaFile> {
+    exclude(list, &dir.filter)
+        .into_iter()
+        .filter(|f| dir.only.as_ref().is_none_or(|name| &f.name == name))
+        .collect()
+}
+
+/// Remove the excluded files of the list.
+pub fn exclude(list: Vec<MetaFile>, filter: &Filter) -> Vec<MetaFile> {
+    list.into_iter()
+        .filter(|f| !filter.excluded(&f.name, f.dir))
+        .collect()
+}
+
+/// Check if the listing has an ignore file.
+fn has_ignore(list: &[MetaFile]) -> bool {
+    list.iter().any(|f| !f.dir && f.name == Path::new(IGNORE))
+}
+
+/// Check if the remote directory has an ignore file, without listing it.
+fn remote_ignore(a: &Assets, dir: &Dir) -> bool {
+    dir.remote_path(Path::new(IGNORE))
+        .is_ok_and(|p| a.sftp.lstat(&p).is_ok_and(|s| !s.is_dir()))
+}
+
+/// Add to the filter the patterns of the local and, if `remote`, the remote
+/// ignore files.
+fn ignore_file(a: &Assets, dir: &Dir, remote: bool) -> Arc<Filter> {
+    let mut patterns = std::fs::read_to_string(dir.local.join(IGNORE)).unwrap_or_default();
+
+    if remote {
+        let r = dir
+            .remote_path(Path::new(IGNORE))
+            .and_then(|p| read_remote(a, &p));
+        match r {
+            Ok(s) => {
+                patterns.push('\n');
+                patterns.push_str(&s);
+            }
+            Err(err) => a.err(err),
+        }
+    }
+
+    dir.filter.with(&patterns)
+}
+
+/// Read a remote ignore file, decrypted if the content is encrypted.
+pub fn read_remote(a: &Assets, p: &Path) -> Result<String, String> {
+    let mut f = a
+        .sftp
+        .open(p)
+        .map_err(|err| format!("Open remote file {:?} fail {}", p, err))?;
+    let mut s = Vec::new();
+    match &a.c.crypt {
+        Some(c) => c.decrypt(&mut f, &mut s),
+        None => f.read_to_end(&mut s).map(|n| n as u64),
+    }
+    .map_err(|err| format!("Read remote file {:?} fail {}", p, err))?;
+    Ok(String::from_utf8_lossy(&s).into_owned())
+}
+
+/// Check if all the local files of the directory and its sub directories
+/// are the same than in the index of the last run.
+pub fn local_unchanged(a: &Assets, local_dir: &Path, filter: &Arc<Filter>) -> bool {
+    let mut queue = vec![(local_dir.to_path_buf(), filter.clone())];
+    while let Some((local_dir, filter)) = queue.pop() {
+        let filter =
+            filter.with(&std::fs::read_to_string(local_dir.join(IGNORE)).unwrap_or_default());
+        let list = match list_local(a, &local_dir) {
+            Ok(list) => exclude(list, &filter),
+            Err(_) => return false,
+        };
+
+        if a.index.old.get(filter.dir()).is_none()
+            || a.index.old.children(filter.dir()).count() != list.len()
+        {
+            return false;
+        }
+        for f in list.iter() {
+            match a.index.old.get(&filter.path(&f.name)) {
+                Some(e) if !e.local_changed(f) => {}
+                _ => return false,
+            }
+            if f.dir {
+                queue.push((local_dir.join(&f.name), filter.enter(&f.name)));
+            }
+        }
+    }
+    true
+}
+
+/// Check if `unchanged` holds for every file of one side of the directory
+/// and its sub directories, with the path relative to the root.
+pub fn tree_unchanged<U>(a: &Assets, dir: &Dir, remote: bool, unchanged: U) -> Result<bool, String>
+where
+    U: Fn(&str, &MetaFile) -> bool,
+{
+    let mut queue = vec![dir.clone()];
+    while let Some(mut dir) = queue.pop() {
+        let list = match remote {
+            true => decrypt_list(a, &dir, list_remote(a, &dir.remote)?),
+            false => list_local(a, &dir.local)?,
+        };
+        dir.filter = ignore_file(a, &dir, remote && has_ignore(&list));
+        for f in exclude(list, &dir.filter) {
+            if !unchanged(&dir.filter.path(&f.name), &f) {
+                return Ok(false);
+            }
+            if f.dir {
+                queue.push(dir.enter(&f.name)?);
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Create a remote directory and its missing parents.
+pub fn mkdir_all(a: &Assets, path: &Path) -> R {
+    let mut missing = Vec::new();
+    let mut d = Some(path);
+    while let Some(p) = d.filter(|p| !p.as_os_str().is_empty()) {
+        if a.sftp.stat(p).is_ok() {
+            break;
+        }
+        missing.push(p);
+        d = p.parent();
+    }
+    for p in missing.into_iter().rev() {
+        a.act("mkdir", p, None);
+        a.sftp
+            .mkdir(p, 0o0777)
+            .map_err(|err| format!("Make directory {:?} fail {}", p, err))?;
+    }
+    Ok(())
+}
+
+/// Remove a remote directory, except the excluded files. The ignore file
+/// of each removed directory is read first, and kept with what it protects.
+/// The encrypted names are decrypted before the filter, the other files are
+/// kept.
+pub fn remove_dir(a: &Assets, remote_dir: &Path, filter: &Arc<Filter>) -> R {
+    let names = a.c.crypt.as_ref().filter(|_| a.c.encrypt_names);
+    // Empty the directories from the top, then remove them from the bottom.
+    let mut queue = vec![(remote_dir.to_path_buf(), filter.clone())];
+    let mut dirs = Vec::new();
+    while let Some((d, filter)) = queue.pop() {
+        let list = match list_remote(a, &d) {
+            Ok(list) => list,
+            Err(err) if dirs.is_empty() => return Err(err),
+            Err(err) => {
+                a.err(err);
+                dirs.push((d, true, None));
+                continue;
+            }
+        };
+        let mut kept = false;
+        // The remote path of each file, with its name before the encryption.
+        let list: Vec<(PathBuf, MetaFile)> = list
+            .into_iter()
+            .filter_map(|mut f| {
+                let p = d.join(&f.name);
+                if let Some(c) = names {
+                    match c.decrypt_name(&f.name) {
+                        Some(name) => f.name = name,
+                        None => {
+                            a.log("keep", &p, None);
+                            kept = true;
+                            return None;
+                        }
+                    }
+                }
+                Some((p, f))
+            })
+            .collect();
+        let filter = match list
+            .iter()
+            .find(|(_, f)| !f.dir && f.name == Path::new(IGNORE))
+        {
+            Some((p, _)) => match read_remote(a, p) {
+                Ok(patterns) => filter.with(&patterns),
+                Err(err) => {
+                    a.err(err);
+                    dirs.push((d, true, None));
+                    continue;
+                }
+            },
+            None => filter,
+        };
+        let mut ignore = None;
+        for (p, f) in list.into_iter() {
+            if filter.excluded(&f.name, f.dir) {
+                kept = true;
+            } else if f.dir {
+                queue.push((p, filter.enter(&f.name)));
+            } else if f.name == Path::new(IGNORE) {
+                ignore = Some(p);
+            } else {
+                a.act("rm", &p, None);
+                if let Err(err) = a.sftp.unlink(&p) {
+                    a.err(format!("Remove file {:?} fail {}", p, err));
+                    kept = true;
+                }
+            }
+        }
+        dirs.push((d, kept, ignore));
+    }
+
+    remove_empty(
+        a,
+        dirs,
+        |p| {
+            a.sftp
+                .unlink(p)
+                .map_err(|err| format!("Remove file {:?} fail {}", p, err))
+        },
+        |d| {
+            a.sftp
+                .rmdir(d)
+                .map_err(|err| format!("Remove empty directory {:?} fail {}", d, err))
+        },
+    );
+    Ok(())
+}
+
+/// Remove a local directory, except the excluded files. The ignore file of
+/// each removed directory is read first, and kept with what it protects.
+pub fn remove_local_dir(a: &Assets, local_dir: &Path, filter: &Arc<Filter>) -> R {
+    let mut queue = vec![(local_dir.to_path_buf(), filter.clone())];
+    let mut dirs = Vec::new();
+    while let Some((d, filter)) = queue.pop() {
+        let list = match list_local(a, &d) {
+            Ok(list) => list,
+            Err(err) if dirs.is_empty() => return Err(err),
+            Err(err) => {
+                a.err(err);
+                dirs.push((d, true, None));
+                continue;
+            }
+        };
+        let filter = match std::fs::read_to_string(d.join(IGNORE)) {
+            Ok(patterns) => filter.with(&patterns),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => filter,
+            Err(err) => {
+                a.err(format!("Read {:?} fail: {}", d.join(IGNORE), err));
+                dirs.push((d, true, None));
+                continue;
+            }
+        };
+        let (mut kept, mut ignore) = (false, None);
+        for f in list.into_iter() {
+            let p = d.join(&f.name);
+            if filter.excluded(&f.name, f.dir) {
+                kept = true;
+            } else if f.dir {
+                queue.push((p, filter.enter(&f.name)));
+            } else if f.name == Path::new(IGNORE) {
+                ignore = Some(p);
+            } else {
+                a.act("rm", &p, None);
+                if let Err(err) = std::fs::remove_file(&p) {
+                    a.err(format!("rm of {:?} fail {}", p, err));
+                    kept = true;
+                }
+            }
+        }
+        dirs.push((d, kept, ignore));
+    }
+
+    remove_empty(
+        a,
+        dirs,
+        |p| std::fs::remove_file(p).map_err(|err| format!("rm of {:?} fail {}", p, err)),
+        |d| std::fs::remove_dir(d).map_err(|err| format!("Remove dir {:?} fail {}", d, err)),
+    );
+    Ok(())
+}
+
+/// Remove the directories from the deepest, with their ignore file. A
+/// directory that keeps a file also keeps its ignore file and its parents.
+fn remove_empty<U, F>(a: &Assets, dirs: Vec<(PathBuf, bool, Option<PathBuf>)>, unlink: U, rmdir: F)
+where
+    U: Fn(&Path) -> R,
+    F: Fn(&Path) -> R,
+{
+    let mut kept_dirs = HashSet::new();
+    for (d, kept, ignore) in dirs.into_iter().rev() {
+        if kept || kept_dirs.contains(&d) {
+            a.log("keep", &d, None);
+        } else {
+            let r = match &ignore {
+                Some(p) => {
+                    a.act("rm", p, None);
+                    unlink(p)
+                }
+                None => Ok(()),
+            }
+            .and_then(|_| {
+                a.act("rmdir", &d, None);
+                rmdir(&d)
+            });
+            match r {
+                Ok(()) => continue,
+                Err(err) => a.err(err),
+            }
+        }
+        if let Some(parent) = d.parent() {
+            kept_dirs.insert(parent.to_path_buf());
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use super::{
+    mkdir_all, print_err, remote_root, scopes, state, upload_couple, walk, Addr, Assets, Config,
+    Dir, Index, MetaFile, Tracker, R,
+};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr};
+use std::fs::read_dir;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// The events of the local files that need an upload.
+const EVENTS: u32 = libc::IN_CLOSE_WRITE
+    | libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_DELETE_SELF;
+
+/// The inotify watches of a local tree.
+struct Inotify {
+    fd: libc::c_int,
+    a: Addr,
+    ansi: bool,
+    root: PathBuf,
+    /// The relative path of the directory of each watch.
+    dirs: HashMap<libc::c_int, PathBuf>,
+}
+
+impl Inotify {
+    fn new(a: &Addr, ansi: bool) -> Result<Inotify, String> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(format!(
+                "Init inotify fail: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        let mut i = Inotify {
+            fd,
+            a: a.clone(),
+            ansi,
+            root: PathBuf::from(&a.digest),
+            dirs: HashMap::new(),
+        };
+        i.add(PathBuf::new())?;
+        Ok(i)
+    }
+    /// Watch the directory `rel` and its sub directories.
+    fn add(&mut self, rel: PathBuf) -> R {
+        let mut queue = vec![rel];
+        while let Some(rel) = queue.pop() {
+            let path = self.root.join(&rel);
+            let c = CString::new(path.as_os_str().as_bytes())
+                .map_err(|err| format!("Watch {:?} fail: {}", path, err))?;
+            let wd = unsafe { libc::inotify_add_watch(self.fd, c.as_ptr(), EVENTS) };
+            if wd < 0 {
+                return Err(format!(
+                    "Watch {:?} fail: {}",
+                    path,
+                    std::io::Error::last_os_error()
+                ));
+            }
+            self.dirs.insert(wd, rel.clone());
+            for e in read_dir(&path)
+                .map_err(|err| format!("Read {:?} fail: {}", path, err))?
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+            {
+                queue.push(rel.join(e.file_name()));
+            }
+        }
+        Ok(())
+    }
+    /// Stop to watch the directory `rel` and its sub directories, moved.
+    fn remove(&mut self, rel: &Path) {
+        let fd = self.fd;
+        self.dirs.retain(|wd, dir| match dir.starts_with(rel) {
+            true => {
+                unsafe { libc::inotify_rm_watch(fd, *wd) };
+                false
+            }
+            false => true,
+        });
+    }
+    /// Wait for events, at most `timeout`. Return false on timeout.
+    fn wait(&self, timeout: Option<Duration>) -> Result<bool, String> {
+        let mut p = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ms = timeout.map_or(-1, |t| t.as_millis() as libc::c_int);
+        match unsafe { libc::poll(&mut p, 1, ms) } {
+            n if n < 0 => {
+                let err = std::io::Error::last_os_error();
+                match err.kind() {
+                    std::io::ErrorKind::Interrupted => Ok(true),
+                    _ => Err(format!("Wait for inotify fail: {}", err)),
+                }
+            }
+            n => Ok(n > 0),
+        }
+    }
+    /// Read the pending events, add the changed paths. Return true if events
+    /// were lost, then the whole tree must be compared.
+    fn read(&mut self, changed: &mut HashSet<PathBuf>) -> Result<bool, String> {
+        let mut buf = [0u8; 64 * 1024];
+        let mut lost = false;
+        loop {
+            let n =
+                unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if n < 0 {
+                let err = std::io::Error::last_os_error();
+                return match err.kind() {
+                    std::io::ErrorKind::WouldBlock => Ok(lost),
+                    std::io::ErrorKind::Interrupted => continue,
+                    _ => Err(format!("Read inotify fail: {}", err)),
+                };
+            }
+            let mut i = 0;
+            while i + 16 <= n as usize {
+                let int = |o: usize| {
+                    let mut b = [0u8; 4];
+                    b.copy_from_slice(&buf[i + o..i + o + 4]);
+                    u32::from_ne_bytes(b)
+                };
+                let (wd, mask, len) = (int(0) as libc::c_int, int(4), int(12) as usize);
+                let name = &buf[i + 16..i + 16 + len];
+                let name =
+                    OsStr::from_bytes(&name[..name.iter().position(|&b| b == 0).unwrap_or(len)]);
+                i += 16 + len;
+
+                if mask & libc::IN_Q_OVERFLOW != 0 {
+                    lost = true;
+                    continue;
+                }
+                if mask & libc::IN_IGNORED != 0 {
+                    self.dirs.remove(&wd);
+                    continue;
+                }
+                let rel = match self.dirs.get(&wd) {
+                    Some(dir) if !name.is_empty() => dir.join(name),
+                    Some(dir) => dir.clone(),
+                    None => continue,
+                };
+                if mask & libc::IN_ISDIR != 0 && mask & libc::IN_MOVED_FROM != 0 {
+                    self.remove(&rel);
+                }
+                if mask & libc::IN_ISDIR != 0 && mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                    // The files created before the watch are compared with it.
+                    if let Err(err) = self.add(rel.clone()) {
+                        print_err(err, &self.a, self.ansi);
+                    }
+                }
+                changed.insert(rel);
+            }
+        }
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Keep only the paths whose parent isn't in the set.
+fn tops(changed: HashSet<PathBuf>) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = changed.into_iter().collect();
+    paths.sort();
+    let mut tops: Vec<PathBuf> = Vec::new();
+    for p in paths.into_iter() {
+        if !tops.last().is_some_and(|t| p.starts_with(t)) {
+            tops.push(p);
+        }
+    }
+    tops
+}
+#[test]
+fn test_tops() {
+    let changed: HashSet<PathBuf> = ["a/b", "a", "c/d", "c/e/f", "c/e", "cd"]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+    let expected: Vec<PathBuf> = ["a", "c/d", "c/e", "cd"]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+    assert_eq!(tops(changed), expected);
+}
+
+/// Limit the changed paths to the `--path` options: a changed parent of an
+/// option is replaced by the option.
+fn limit(paths: Vec<PathBuf>, only: &[PathBuf]) -> Vec<PathBuf> {
+    if only.is_empty() {
+        return paths;
+    }
+    let mut limited = HashSet::new();
+    for p in paths.into_iter() {
+        for o in only.iter() {
+            if p.starts_with(o) {
+                limited.insert(p.clone());
+            } else if o.starts_with(&p) {
+                limited.insert(o.clone());
+            }
+        }
+    }
+    tops(limited)
+}
+#[test]
+fn test_limit() {
+    let paths = |v: &[&str]| v.iter().map(PathBuf::from).collect::<Vec<PathBuf>>();
+    assert_eq!(limit(paths(&["a", "b"]), &[]), paths(&["a", "b"]));
+    assert_eq!(
+        limit(paths(&["a", "b/c", "d/e"]), &paths(&["a/x", "b", "c"])),
+        paths(&["a/x", "b/c"])
+    );
+}
+
+/// Open the assets, retry until the host answers.
+fn open(a: &Addr, c: &Arc<Config>) -> Assets {
+    let mut delay = Duration::from_secs(1);
+    loop {
+        match Assets::new(a.clone(), c.clone()) {
+            Ok(assets) => return assets,
+            Err(err) => {
+                print_err(format!("{}, retry in {:?}", err, delay), a, c.ansi);
+                sleep(delay);
+                delay = (delay * 2).min(Duration::from_secs(60));
+            }
+        }
+    }
+}
+
+/// Open the assets again, the old ones are dropped quietly.
+fn reconnect(assets: &mut Assets, a: &Addr, c: &Arc<Config>) {
+    assets.quiet = true;
+    *assets = open(a, c);
+}
+
+/// Check if the local file was modified since the last run: it isn't the
+/// one of the index, or without index the remote one has another size. The
+/// mtimes aren't copied, they can't be compared between the sides.
+fn modified(a: &Assets, dir: &Dir, remote: &MetaFile, local: &MetaFile) -> bool {
+    match a.index.old.get(&dir.filter.path(&local.name)) {
+        Some(e) => e.local_changed(local),
+        None => local.size != remote.size,
+    }
+}
+
+/// Upload the changed paths, or the whole tree if `paths` is empty. Unlike
+/// `upload`, the modified files replace the remote ones. The index is saved
+/// only if every file is uploaded.
+fn upload_paths(assets: &Assets, paths: &[PathBuf]) -> R {
+    // The errors of the last upload are already printed.
+    assets.errors.store(0, Ordering::SeqCst);
+    let root = Dir::root(
+        assets,
+        remote_root(assets)?,
+        PathBuf::from(&assets.a.digest),
+    );
+    let dirs = match paths.is_empty() {
+        true => scopes(assets, root)?,
+        false => {
+            let mut dirs = Vec::new();
+            for p in limit(paths.to_vec(), &assets.c.paths).iter() {
+                // The changes of the excluded paths are ignored.
+                dirs.extend(root.scope(assets, p)?);
+            }
+            assets
+                .index
+                .keep_if(|p| !dirs.iter().any(|d| d.contains(p)));
+            dirs
+        }
+    };
+    for dir in dirs {
+        mkdir_all(assets, &dir.remote)?;
+        walk(assets, dir, |a, couple, dir, queue| match couple {
+            (Some(remote), Some(local))
+                if !remote.dir && !local.dir && modified(a, dir, remote, local) =>
+            {
+                upload_couple(a, None, &(None, Some(local.clone())), dir, queue)
+            }
+            _ => upload_couple(a, None, couple, dir, queue),
+        })?;
+    }
+    assets.transfers.join();
+    assets.result()?;
+    assets.index.save(&state(&assets.a, "index"))
+}
+
+/// Upload the local changes of the host directory as they happen. The
+/// changes are grouped until nothing changes for `debounce`.
+pub fn watch(a: Addr, c: Arc<Config>, debounce: Duration) -> R {
+    std::fs::create_dir_all(&a.digest)
+        .map_err(|err| format!("Create {:?} directory fail: {}", &a.digest, err))?;
+    let mut inotify = Inotify::new(&a, c.ansi)?;
+    let mut assets = open(&a, &c);
+    // The first upload is retried until it succeeds.
+    let mut delay = Duration::from_secs(1);
+    while let Err(err) = upload_paths(&assets, &[]) {
+        assets.err(format!("{}, retry in {:?}", err, delay));
+        sleep(delay);
+        delay = (delay * 2).min(Duration::from_secs(60));
+        reconnect(&mut assets, &a, &c);
+    }
+
+    let mut changed = HashSet::new();
+    // Events were lost, or a failed upload was of the whole tree.
+    let mut all = false;
+    loop {
+        // The index of the next changes is the one just saved.
+        assets.index = Tracker::new(Index::load(&state(&assets.a, "index"))?);
+        inotify.wait(None)?;
+        all |= inotify.read(&mut changed)?;
+        while inotify.wait(Some(debounce))? {
+            all |= inotify.read(&mut changed)?;
+        }
+        let paths = tops(std::mem::take(&mut changed));
+        let paths = match all {
+            true => Vec::new(),
+            false => paths,
+        };
+
+        // The session can be closed by the server while idle.
+        if assets.sftp.stat(Path::new(&assets.a.root)).is_err() {
+            assets.err("The session is closed, reconnect".to_string());
+            reconnect(&mut assets, &a, &c);
+        }
+        let r = upload_paths(&assets, &paths).or_else(|err| {
+            assets.err(err);
+            reconnect(&mut assets, &a, &c);
+            upload_paths(&assets, &paths)
+        });
+        match r {
+            Ok(()) => all = false,
+            // Keep the paths for the next changes.
+            Err(err) => {
+                assets.err(format!("{}, retry at the next change", err));
+                changed.extend(paths);
+            }
+        }
+    }
+}